@@ -1,20 +1,21 @@
-use crate::structs::{
-    self,
-    client::Client,
-    command::CommandType::{BecomeClient, BecomeHost, Clear, Exit},
-    host::Host,
-    user::{User, UserTrait},
+use crate::{
+    global::config::Config,
+    structs::{
+        self,
+        client::Client,
+        command::CommandType::{BecomeClient, BecomeHost, Clear, Exit},
+        host::Host,
+        user::{User, UserTrait},
+    },
 };
 use colored::Colorize;
 use std::io::{self, BufRead, Write};
 
-pub async fn read_commands(
-    name: &String,
-    host: &String,
-    client_port: &u16,
-    host_port: &u16,
-    user: &mut Option<User>,
-) {
+pub async fn read_commands(config: &Config, user: &mut Option<User>) {
+    let host = &config.host;
+    let client_port = &config.client_port;
+    let host_port = &config.host_port;
+
     let stdin = io::stdin();
     let stdout = io::stdout();
     let mut writer = io::BufWriter::new(stdout);
@@ -23,7 +24,7 @@ pub async fn read_commands(
     let mut cmd_str = String::from("");
     update_prompt_str(
         &mut cmd_str,
-        &host,
+        host,
         client_port,
         host_port,
         String::from("Cli"),
@@ -49,6 +50,9 @@ pub async fn read_commands(
         // Create or switch user
         match c.command_type.as_ref().unwrap() {
             Exit => {
+                if let Some(u) = user.as_mut() {
+                    u.shutdown(host, *client_port).await;
+                }
                 write!(&mut writer, "\x1B[2J\x1B[1;1H").unwrap();
                 writer.flush().unwrap();
                 return;
@@ -59,35 +63,37 @@ pub async fn read_commands(
                 continue;
             }
             BecomeClient => {
-                *user = Some(User::Client(Client::new(name.clone())));
+                if let Some(u) = user.as_mut() {
+                    u.shutdown(host, *client_port).await;
+                }
+                *user = Some(User::Client(Client::new(config)));
                 update_prompt_str(
                     &mut cmd_str,
-                    &host,
+                    host,
                     client_port,
                     host_port,
                     String::from("Client"),
                 );
                 if let User::Client(u) = user.as_mut().unwrap() {
-                    u.search_for_hosts(host.clone(), client_port.clone()).await;
+                    u.start_discovery(host.to_string(), *client_port, *host_port)
+                        .await;
                 }
                 continue;
             }
             BecomeHost => {
-                *user = Some(User::Host(Host::new(name.clone())));
+                if let Some(u) = user.as_mut() {
+                    u.shutdown(host, *client_port).await;
+                }
+                *user = Some(User::Host(Host::new(config)));
                 update_prompt_str(
                     &mut cmd_str,
-                    &host,
+                    host,
                     client_port,
                     host_port,
                     String::from("Host"),
                 );
                 if let User::Host(u) = user.as_mut().unwrap() {
-                    u.broadcast_discovery_message(
-                        host.clone(),
-                        client_port.clone(),
-                        host_port.clone(),
-                    )
-                    .await;
+                    u.start(host.to_string(), *client_port, *host_port).await;
                 }
                 continue;
             }
@@ -121,7 +127,7 @@ pub async fn read_commands(
 
 fn update_prompt_str(
     s: &mut String,
-    host: &String,
+    host: &str,
     client_port: &u16,
     host_port: &u16,
     client_type: String,
@@ -1 +1,79 @@
+use std::{
+    env,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6},
+    path::PathBuf,
+    time::Duration,
+};
 
+// How discovery packets reach the clients
+#[derive(Clone, Copy, Debug)]
+pub enum DiscoveryMode {
+    Broadcast,
+    Multicast { group: Ipv4Addr, ttl: u32 },
+    // Link-local IPv6 multicast (ff02::/16) on one interface
+    MulticastV6 { group: Ipv6Addr, interface: u32 },
+}
+
+impl DiscoveryMode {
+    // Address the host sends its announcements to
+    pub fn target(&self, client_port: u16) -> SocketAddr {
+        match self {
+            DiscoveryMode::Broadcast => {
+                SocketAddr::new(IpAddr::V4(Ipv4Addr::BROADCAST), client_port)
+            }
+            DiscoveryMode::Multicast { group, .. } => {
+                SocketAddr::new(IpAddr::V4(*group), client_port)
+            }
+            DiscoveryMode::MulticastV6 { group, interface } => {
+                SocketAddr::V6(SocketAddrV6::new(*group, client_port, 0, *interface))
+            }
+        }
+    }
+}
+
+// Settings for one run of the program, built from the cli arguments
+pub struct Config {
+    pub name: String,
+    pub room: String,
+    pub topic: String,
+    // Most clients a host lets in, 0 means unlimited
+    pub capacity: u16,
+    // Password a host requires of clients, and a client sends when joining
+    pub password: Option<String>,
+    // Discovery namespace a host announces itself in
+    pub group: String,
+    // Groups a client records hosts from, empty means every group
+    pub search_groups: Vec<String>,
+    pub host: String,
+    pub client_port: u16,
+    pub host_port: u16,
+    pub host_ttl: Duration,
+    pub discovery: DiscoveryMode,
+    // Steady interval between announcements
+    pub announce_interval: Duration,
+    // First interval after start or a change, doubled until the steady one
+    pub announce_burst: Duration,
+    // Random part of each interval, 0.1 is +-10%
+    pub announce_jitter: f64,
+    // Interfaces to broadcast on, empty means every interface
+    pub interfaces: Vec<String>,
+    // Shared team secret, discovery packets are signed and checked with it
+    pub secret: Option<Vec<u8>>,
+    // Where the persistent host id is kept
+    pub id_file: PathBuf,
+    // Where a client caches the hosts it found
+    pub hosts_file: PathBuf,
+    // Relays a discovery packet may pass before it is dropped
+    pub max_hops: u8,
+    // Hosts a client probes by unicast, for networks without broadcast or multicast
+    pub peers: Vec<String>,
+}
+
+// Per user directory for files that outlive a run, ~/.config/udp-discovery
+pub fn data_dir() -> PathBuf {
+    let base = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|h| PathBuf::from(h).join(".config")))
+        .unwrap_or_else(|| PathBuf::from("."));
+    base.join("udp-discovery")
+}
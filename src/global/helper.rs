@@ -1,5 +1,18 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+use super::config::DiscoveryMode;
+
 use tokio::{
     io::{self, AsyncReadExt},
+    net::UdpSocket,
     sync::watch::Sender,
     task::{self, JoinHandle},
 };
@@ -9,12 +22,95 @@ pub async fn quit_task_handler(shutdown_tx: Sender<bool>) -> JoinHandle<()> {
         let mut stdin = io::stdin();
         let mut input = [0u8; 2];
         loop {
-            if let Ok(n) = stdin.read_exact(&mut input).await {
-                if n == 2 && input[0] == b'q' && input[1] == b'\n' {
-                    let _ = shutdown_tx.send(true);
-                    break;
-                }
+            if let Ok(n) = stdin.read_exact(&mut input).await
+                && n == 2
+                && input[0] == b'q'
+                && input[1] == b'\n'
+            {
+                let _ = shutdown_tx.send(true);
+                break;
             }
         }
     })
 }
+
+// Parse an ip with an optional `%scope` suffix, e.g. fe80::1%2
+pub fn parse_scoped_ip(s: &str) -> Option<(IpAddr, u32)> {
+    match s.split_once('%') {
+        Some((ip, scope)) => Some((IpAddr::V6(ip.parse().ok()?), scope.parse().ok()?)),
+        None => Some((s.parse().ok()?, 0)),
+    }
+}
+
+pub fn scoped_socket_addr(ip: IpAddr, port: u16, scope: u32) -> SocketAddr {
+    match ip {
+        IpAddr::V4(ip) => SocketAddr::V4(SocketAddrV4::new(ip, port)),
+        IpAddr::V6(ip) => SocketAddr::V6(SocketAddrV6::new(ip, port, 0, scope)),
+    }
+}
+
+// Local address to bind when talking to `peer`, `--ip` of the other family
+// falls back to the unspecified address of the peer's family
+pub fn bind_addr(host: &str, port: u16, peer: &SocketAddr) -> SocketAddr {
+    match parse_scoped_ip(host) {
+        Some((ip, scope)) if ip.is_ipv4() == peer.is_ipv4() => scoped_socket_addr(ip, port, scope),
+        _ if peer.is_ipv4() => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port),
+        _ => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port),
+    }
+}
+
+// Make `socket` receive discovery traffic sent in `mode`
+pub fn join_discovery_group(socket: &UdpSocket, mode: DiscoveryMode, host: &str) -> io::Result<()> {
+    match mode {
+        // Broadcast is received as is
+        DiscoveryMode::Broadcast => socket.set_broadcast(true),
+        DiscoveryMode::Multicast { group, .. } => {
+            let interface = host.parse::<Ipv4Addr>().unwrap_or(Ipv4Addr::UNSPECIFIED);
+            socket.join_multicast_v4(group, interface)
+        }
+        DiscoveryMode::MulticastV6 { group, interface } => {
+            socket.join_multicast_v6(&group, interface)
+        }
+    }
+}
+
+// UDP socket that other processes can bind to the same port as well,
+// broadcast and multicast datagrams are delivered to each of them
+pub fn bind_shared_udp(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+// Unpredictable enough for ids and nonces, not for key material
+pub fn random_u64() -> u64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u32(std::process::id());
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    hasher.write_u128(now_millis() as u128);
+    hasher.finish()
+}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+// 42s, 5m, 3h or 2d
+pub fn format_age(age: Duration) -> String {
+    let secs = age.as_secs();
+    match secs {
+        0..60 => format!("{}s", secs),
+        60..3600 => format!("{}m", secs / 60),
+        3600..86400 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    }
+}
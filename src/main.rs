@@ -2,23 +2,160 @@ mod cmd;
 mod global;
 mod structs;
 
-use clap::Parser;
-use structs::user::User;
+use std::{
+    fs,
+    net::{Ipv4Addr, Ipv6Addr},
+    path::PathBuf,
+    time::Duration,
+};
+
+use clap::{Parser, ValueEnum};
+use global::config::{self, Config, DiscoveryMode};
+use structs::{relay::Relay, user::User};
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Mode {
+    Broadcast,
+    Multicast,
+    Multicast6,
+}
 
 #[derive(Parser)]
 struct Cli {
     #[arg(short = 'n', long = "name")]
     name: String,
+    #[arg(short = 'r', long = "room", default_value = "general")]
+    room: String,
+    /// Topic of the room, shown to clients
+    #[arg(long = "topic", default_value = "")]
+    topic: String,
+    /// Most clients the host lets in, 0 means unlimited
+    #[arg(long = "capacity", default_value_t = 0)]
+    capacity: u16,
+    /// Password clients need to join the room, a client sends it when joining
+    #[arg(long = "password")]
+    password: Option<String>,
+    /// Discovery group (namespace) the host announces itself in
+    #[arg(short = 'g', long = "group", default_value = "default")]
+    group: String,
+    /// Comma separated groups the client lists hosts from, default is all
+    #[arg(long = "search", value_delimiter = ',')]
+    search: Vec<String>,
     #[arg(short = 'i', long = "ip")]
     host: String,
     #[arg(long = "host-port")]
     hport: u16,
     #[arg(long = "client-port")]
     cport: u16,
+    /// Seconds without an announcement before a host is dropped
+    #[arg(long = "host-ttl", default_value_t = 5)]
+    host_ttl: u64,
+    /// Seconds between periodic announcements, clients also probe on start
+    #[arg(long = "announce-interval", default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    announce_interval: u64,
+    /// Milliseconds between the first announcements, doubled up to `--announce-interval`
+    #[arg(long = "announce-burst", default_value_t = 200, value_parser = clap::value_parser!(u64).range(1..))]
+    announce_burst: u64,
+    /// Random +-percent applied to every announcement interval
+    #[arg(long = "announce-jitter", default_value_t = 10, value_parser = clap::value_parser!(u8).range(0..=100))]
+    announce_jitter: u8,
+    /// Relay discovery packets between the `--interfaces` instead of starting the prompt
+    #[arg(long = "relay")]
+    relay: bool,
+    /// Relays a discovery packet may pass before it is dropped
+    #[arg(long = "max-hops", default_value_t = 4)]
+    max_hops: u8,
+    /// Comma separated hosts to probe by unicast, ip, ip:port or name:port
+    #[arg(long = "peers", value_delimiter = ',')]
+    peers: Vec<String>,
+    /// Comma separated interface names to broadcast on, default is all
+    #[arg(long = "interfaces", value_delimiter = ',')]
+    interfaces: Vec<String>,
+    /// Shared team secret for signing discovery packets
+    #[arg(long = "secret", conflicts_with = "secret_file")]
+    secret: Option<String>,
+    /// File holding the shared team secret
+    #[arg(long = "secret-file")]
+    secret_file: Option<PathBuf>,
+    /// File holding the persistent host id, default ~/.config/udp-discovery/host-id
+    #[arg(long = "id-file")]
+    id_file: Option<PathBuf>,
+    /// File caching the hosts a client found, default ~/.config/udp-discovery/known-hosts
+    #[arg(long = "hosts-file")]
+    hosts_file: Option<PathBuf>,
+    /// How discovery packets are sent
+    #[arg(long = "discovery", value_enum, default_value_t = Mode::Broadcast)]
+    discovery: Mode,
+    /// Multicast group used with `--discovery multicast`
+    #[arg(long = "multicast-group", default_value = "239.255.42.99")]
+    multicast_group: Ipv4Addr,
+    /// Multicast TTL, 1 keeps packets on the local segment
+    #[arg(long = "multicast-ttl", default_value_t = 1)]
+    multicast_ttl: u32,
+    /// Link-local group used with `--discovery multicast6`
+    #[arg(long = "multicast6-group", default_value = "ff02::4242")]
+    multicast6_group: Ipv6Addr,
+    /// Interface index for IPv6 multicast, 0 lets the OS choose
+    #[arg(long = "interface", default_value_t = 0)]
+    interface: u32,
 }
 #[tokio::main]
 async fn main() {
     let args = Cli::parse();
+    let secret = match (args.secret, args.secret_file) {
+        (Some(s), _) => Some(s.into_bytes()),
+        (None, Some(path)) => match fs::read_to_string(&path) {
+            Ok(s) => Some(s.trim().as_bytes().to_vec()),
+            Err(e) => {
+                eprintln!("Failed to read secret file {}, Error: {e}", path.display());
+                return;
+            }
+        },
+        (None, None) => None,
+    };
+    let config = Config {
+        name: args.name,
+        room: args.room,
+        topic: args.topic,
+        capacity: args.capacity,
+        password: args.password,
+        group: args.group,
+        search_groups: args.search,
+        host: args.host,
+        client_port: args.cport,
+        host_port: args.hport,
+        host_ttl: Duration::from_secs(args.host_ttl),
+        announce_interval: Duration::from_secs(args.announce_interval),
+        announce_burst: Duration::from_millis(args.announce_burst),
+        announce_jitter: f64::from(args.announce_jitter) / 100.0,
+        interfaces: args.interfaces,
+        max_hops: args.max_hops,
+        peers: args.peers,
+        secret,
+        id_file: args
+            .id_file
+            .unwrap_or_else(|| config::data_dir().join("host-id")),
+        hosts_file: args
+            .hosts_file
+            .unwrap_or_else(|| config::data_dir().join("known-hosts")),
+        discovery: match args.discovery {
+            Mode::Broadcast => DiscoveryMode::Broadcast,
+            Mode::Multicast => DiscoveryMode::Multicast {
+                group: args.multicast_group,
+                ttl: args.multicast_ttl,
+            },
+            Mode::Multicast6 => DiscoveryMode::MulticastV6 {
+                group: args.multicast6_group,
+                interface: args.interface,
+            },
+        },
+    };
+    if args.relay {
+        Relay::new(&config)
+            .run(&config.host, config.client_port)
+            .await;
+        return;
+    }
     let mut user: Option<User> = None;
-    cmd::read_commands(&args.name, &args.host, &args.cport, &args.hport, &mut user).await;
+    cmd::read_commands(&config, &mut user).await;
 }
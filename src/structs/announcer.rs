@@ -0,0 +1,318 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+use if_addrs::{IfAddr, get_if_addrs};
+use socket2::SockRef;
+use tokio::net::UdpSocket;
+
+use crate::global::{
+    config::DiscoveryMode,
+    helper::{bind_addr, random_u64},
+};
+
+use super::auth::PacketAuth;
+
+const DEFAULT_ROUTE: &str = "default";
+
+// One socket per destination, in broadcast and IPv4 multicast mode that is one per interface
+struct Route {
+    interface: String,
+    socket: UdpSocket,
+    target: SocketAddr,
+}
+
+// Sends discovery packets out of every usable interface
+pub struct Announcer {
+    routes: Vec<Route>,
+    // What the routes were built from, kept to rebuild them when addresses change
+    host: String,
+    mode: DiscoveryMode,
+    client_port: u16,
+    interfaces: Vec<String>,
+}
+
+impl Announcer {
+    // `interfaces` is an allow-list of interface names, empty means all of them
+    pub async fn bind(
+        host: &str,
+        mode: DiscoveryMode,
+        client_port: u16,
+        interfaces: &[String],
+    ) -> io::Result<Announcer> {
+        let mut routes = Vec::new();
+
+        if !matches!(mode, DiscoveryMode::MulticastV6 { .. }) {
+            for (name, ip, broadcast) in broadcast_interfaces(interfaces) {
+                let socket = UdpSocket::bind(SocketAddr::new(IpAddr::V4(ip), 0)).await?;
+                let target = match mode {
+                    DiscoveryMode::Multicast { group, ttl } => {
+                        // The bound address does not pick the multicast interface, this does
+                        SockRef::from(&socket).set_multicast_if_v4(&ip)?;
+                        socket.set_multicast_ttl_v4(ttl)?;
+                        socket.set_multicast_loop_v4(true)?;
+                        SocketAddr::new(IpAddr::V4(group), client_port)
+                    }
+                    _ => {
+                        socket.set_broadcast(true)?;
+                        SocketAddr::new(IpAddr::V4(broadcast), client_port)
+                    }
+                };
+                routes.push(Route {
+                    interface: name,
+                    socket,
+                    target,
+                });
+            }
+            if routes.is_empty() && !interfaces.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!(
+                        "None of the interfaces {:?} has an IPv4 address",
+                        interfaces
+                    ),
+                ));
+            }
+        }
+
+        // IPv6 multicast, or no interface could be listed, use a single socket on `--ip`
+        if routes.is_empty() {
+            let target = mode.target(client_port);
+            let socket = UdpSocket::bind(bind_addr(host, 0, &target)).await?;
+            match mode {
+                DiscoveryMode::Broadcast => socket.set_broadcast(true)?,
+                DiscoveryMode::Multicast { ttl, .. } => {
+                    socket.set_multicast_ttl_v4(ttl)?;
+                    // Let clients on this machine hear the host too
+                    socket.set_multicast_loop_v4(true)?;
+                }
+                DiscoveryMode::MulticastV6 { .. } => socket.set_multicast_loop_v6(true)?,
+            }
+            routes.push(Route {
+                interface: String::from(DEFAULT_ROUTE),
+                socket,
+                target,
+            });
+        }
+
+        Ok(Announcer {
+            routes,
+            host: host.to_string(),
+            mode,
+            client_port,
+            interfaces: interfaces.to_vec(),
+        })
+    }
+
+    // True when the IPv4 interfaces or their addresses no longer match the routes
+    pub fn addresses_changed(&self) -> bool {
+        if matches!(self.mode, DiscoveryMode::MulticastV6 { .. }) {
+            return false;
+        }
+        let current: Vec<(String, IpAddr)> = broadcast_interfaces(&self.interfaces)
+            .into_iter()
+            .map(|(name, ip, _)| (name, IpAddr::V4(ip)))
+            .collect();
+        if current.is_empty() {
+            return self.routes.iter().any(|r| r.interface != DEFAULT_ROUTE);
+        }
+        current.len() != self.routes.len()
+            || !self.routes.iter().all(|r| {
+                r.socket
+                    .local_addr()
+                    .is_ok_and(|a| current.contains(&(r.interface.clone(), a.ip())))
+            })
+    }
+
+    // Bind fresh routes for the current interfaces
+    pub async fn rebind(&mut self) -> io::Result<()> {
+        *self = Announcer::bind(&self.host, self.mode, self.client_port, &self.interfaces).await?;
+        Ok(())
+    }
+
+    pub fn print_routes(&self) {
+        for route in &self.routes {
+            println!(
+                "> Announcing on {} ({} -> {})",
+                route.interface,
+                route
+                    .socket
+                    .local_addr()
+                    .map(|a| a.ip().to_string())
+                    .unwrap_or_default(),
+                route.target
+            );
+        }
+    }
+
+    // Seal and send on every route, fails only if no route could send
+    pub async fn send(&self, auth: &PacketAuth, packet: &[u8]) -> io::Result<()> {
+        self.send_except(auth, packet, None).await
+    }
+
+    // Send on every route but the one of interface `skip`, each copy sealed on its own
+    // so a client hearing several routes does not take the second for a replay
+    pub async fn send_except(
+        &self,
+        auth: &PacketAuth,
+        packet: &[u8],
+        skip: Option<&str>,
+    ) -> io::Result<()> {
+        let mut last_err = None;
+        let mut sent = false;
+        for route in self
+            .routes
+            .iter()
+            .filter(|r| Some(r.interface.as_str()) != skip)
+        {
+            match route
+                .socket
+                .send_to(&auth.seal(packet.to_vec()), &route.target)
+                .await
+            {
+                Ok(_) => sent = true,
+                Err(e) => last_err = Some(e),
+            }
+        }
+        match last_err {
+            Some(e) if !sent => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+// Shortest delay between two announcements
+const MIN_ANNOUNCE_DELAY: Duration = Duration::from_millis(1);
+
+// When to announce, fast right after start or a change, doubling up to the steady interval
+#[derive(Clone)]
+pub struct AnnounceSchedule {
+    burst: Duration,
+    steady: Duration,
+    // Fraction of each delay added or taken away at random, so hosts do not synchronize
+    jitter: f64,
+    next: Duration,
+}
+
+impl AnnounceSchedule {
+    pub fn new(burst: Duration, steady: Duration, jitter: f64) -> AnnounceSchedule {
+        // A zero delay would never double and announce in a tight loop
+        let burst = burst.max(MIN_ANNOUNCE_DELAY);
+        let steady = steady.max(MIN_ANNOUNCE_DELAY);
+        AnnounceSchedule {
+            burst,
+            steady,
+            jitter,
+            next: burst.min(steady),
+        }
+    }
+
+    pub fn steady(&self) -> Duration {
+        self.steady
+    }
+
+    // Start over with the fast burst
+    pub fn reset(&mut self) {
+        self.next = self.burst.min(self.steady);
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let base = self.next;
+        self.next = (self.next * 2).min(self.steady);
+        let r = random_u64() as f64 / u64::MAX as f64;
+        base.mul_f64(1.0 + self.jitter * (2.0 * r - 1.0))
+    }
+}
+
+// Name of the IPv4 interface whose subnet `ip` is on
+pub fn interface_of(ip: IpAddr) -> Option<String> {
+    let IpAddr::V4(ip) = ip else {
+        return None;
+    };
+    get_if_addrs().ok()?.into_iter().find_map(|i| match i.addr {
+        IfAddr::V4(v4) => {
+            let mask = u32::from(v4.netmask);
+            (u32::from(ip) & mask == u32::from(v4.ip) & mask).then_some(i.name)
+        }
+        IfAddr::V6(_) => None,
+    })
+}
+
+// (name, ip, directed broadcast) of every up, non loopback IPv4 interface
+pub fn broadcast_interfaces(allow: &[String]) -> Vec<(String, Ipv4Addr, Ipv4Addr)> {
+    let ifaces = match get_if_addrs() {
+        Ok(i) => i,
+        Err(e) => {
+            eprintln!("Failed to list interfaces: {}", e);
+            return Vec::new();
+        }
+    };
+
+    ifaces
+        .into_iter()
+        .filter(|i| !i.is_loopback() && !i.is_p2p())
+        .filter(|i| allow.is_empty() || allow.contains(&i.name))
+        .filter_map(|i| match i.addr {
+            IfAddr::V4(v4) => {
+                let broadcast = v4
+                    .broadcast
+                    .unwrap_or(Ipv4Addr::from(u32::from(v4.ip) | !u32::from(v4.netmask)));
+                Some((i.name, v4.ip, broadcast))
+            }
+            IfAddr::V6(_) => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn delays(schedule: &mut AnnounceSchedule, n: usize) -> Vec<Duration> {
+        (0..n).map(|_| schedule.next_delay()).collect()
+    }
+
+    #[test]
+    fn burst_doubles_up_to_the_steady_interval() {
+        let mut schedule =
+            AnnounceSchedule::new(Duration::from_millis(200), Duration::from_secs(1), 0.0);
+        let ms = |v: u64| Duration::from_millis(v);
+        assert_eq!(
+            delays(&mut schedule, 5),
+            [ms(200), ms(400), ms(800), ms(1000), ms(1000)]
+        );
+    }
+
+    #[test]
+    fn reset_starts_the_burst_over() {
+        let mut schedule =
+            AnnounceSchedule::new(Duration::from_millis(100), Duration::from_secs(1), 0.0);
+        delays(&mut schedule, 10);
+        schedule.reset();
+        assert_eq!(schedule.next_delay(), Duration::from_millis(100));
+        assert_eq!(schedule.next_delay(), Duration::from_millis(200));
+    }
+
+    #[test]
+    fn zero_delays_are_clamped() {
+        let mut schedule = AnnounceSchedule::new(Duration::ZERO, Duration::ZERO, 0.0);
+        assert!(delays(&mut schedule, 5).iter().all(|d| !d.is_zero()));
+        let mut schedule = AnnounceSchedule::new(Duration::ZERO, Duration::from_millis(8), 0.0);
+        let ms = |v: u64| Duration::from_millis(v);
+        assert_eq!(
+            delays(&mut schedule, 5),
+            [ms(1), ms(2), ms(4), ms(8), ms(8)]
+        );
+    }
+
+    #[test]
+    fn jitter_stays_within_its_fraction() {
+        let mut schedule =
+            AnnounceSchedule::new(Duration::from_secs(1), Duration::from_secs(1), 0.1);
+        for d in delays(&mut schedule, 100) {
+            assert!(d >= Duration::from_millis(900) && d <= Duration::from_millis(1100));
+        }
+    }
+}
@@ -0,0 +1,190 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::global::helper::{now_millis, random_u64};
+
+use super::discovery::{FLAG_SIGNED, FLAGS_OFFSET, HEADER_LEN};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/*
+ * Auth trailer appended to signed discovery packets
+ *
+ * | timestamp u64 (unix ms) | nonce u64 | hmac-sha256 over everything before it (32 bytes) |
+ */
+const MAC_LEN: usize = 32;
+const TRAILER_LEN: usize = 8 + 8 + MAC_LEN;
+
+// Packets older or newer than this are replays or come from a badly skewed clock
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(30);
+
+// Signs outgoing and verifies incoming discovery packets with a shared team secret
+pub struct PacketAuth {
+    secret: Option<Vec<u8>>,
+    seen_nonces: HashMap<u64, Instant>,
+}
+
+impl PacketAuth {
+    pub fn new(secret: Option<Vec<u8>>) -> PacketAuth {
+        PacketAuth {
+            secret,
+            seen_nonces: HashMap::new(),
+        }
+    }
+
+    // Append the auth trailer, packets are sent as is without a secret. Every copy sent
+    // needs its own seal, a receiver drops a nonce it has seen as a replay
+    pub fn seal(&self, packet: Vec<u8>) -> Vec<u8> {
+        self.seal_at(packet, now_millis(), random_u64())
+    }
+
+    fn seal_at(&self, mut packet: Vec<u8>, timestamp: u64, nonce: u64) -> Vec<u8> {
+        let Some(secret) = &self.secret else {
+            return packet;
+        };
+        packet[FLAGS_OFFSET] |= FLAG_SIGNED;
+        packet.extend_from_slice(&timestamp.to_be_bytes());
+        packet.extend_from_slice(&nonce.to_be_bytes());
+        let mut mac = HmacSha256::new_from_slice(secret).unwrap();
+        mac.update(&packet);
+        packet.extend_from_slice(&mac.finalize().into_bytes());
+        packet
+    }
+
+    // Check the auth trailer and return the packet without it
+    pub fn open<'a>(&mut self, buf: &'a [u8]) -> Result<&'a [u8], String> {
+        if buf.len() < HEADER_LEN {
+            return Err(String::from("Packet too short"));
+        }
+        let signed = buf[FLAGS_OFFSET] & FLAG_SIGNED != 0;
+        let Some(secret) = &self.secret else {
+            // No secret configured, accept everything but still strip the trailer
+            return match signed {
+                true if buf.len() >= HEADER_LEN + TRAILER_LEN => {
+                    Ok(&buf[..buf.len() - TRAILER_LEN])
+                }
+                true => Err(String::from("Packet too short")),
+                false => Ok(buf),
+            };
+        };
+        if !signed {
+            return Err(String::from("Unsigned packet"));
+        }
+        if buf.len() < HEADER_LEN + TRAILER_LEN {
+            return Err(String::from("Packet too short"));
+        }
+
+        let (signed_part, tag) = buf.split_at(buf.len() - MAC_LEN);
+        let mut mac = HmacSha256::new_from_slice(secret).unwrap();
+        mac.update(signed_part);
+        if mac.verify_slice(tag).is_err() {
+            return Err(String::from("Bad signature"));
+        }
+
+        let body_len = buf.len() - TRAILER_LEN;
+        let timestamp = u64::from_be_bytes(buf[body_len..body_len + 8].try_into().unwrap());
+        let nonce = u64::from_be_bytes(buf[body_len + 8..body_len + 16].try_into().unwrap());
+        if now_millis().abs_diff(timestamp) > MAX_CLOCK_SKEW.as_millis() as u64 {
+            return Err(String::from("Stale timestamp"));
+        }
+
+        // Anything older than twice the skew window is rejected by the timestamp check anyway
+        let now = Instant::now();
+        self.seen_nonces
+            .retain(|_, seen| now.duration_since(*seen) < MAX_CLOCK_SKEW * 2);
+        if self.seen_nonces.insert(nonce, now).is_some() {
+            return Err(String::from("Replayed packet"));
+        }
+
+        Ok(&buf[..body_len])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::discovery::DiscoveryPacket;
+
+    fn auth() -> PacketAuth {
+        PacketAuth::new(Some(b"team secret".to_vec()))
+    }
+
+    #[test]
+    fn sealed_packets_open_to_the_original() {
+        let packet = DiscoveryPacket::encode_probe();
+        let sealed = auth().seal(packet.clone());
+        assert_eq!(sealed.len(), packet.len() + TRAILER_LEN);
+        let body = auth().open(&sealed).unwrap().to_vec();
+        assert_eq!(body[FLAGS_OFFSET] & !FLAG_SIGNED, packet[FLAGS_OFFSET]);
+        assert_eq!(body.len(), packet.len());
+    }
+
+    #[test]
+    fn separately_sealed_copies_both_open() {
+        let sender = auth();
+        let mut receiver = auth();
+        let packet = DiscoveryPacket::encode_probe();
+        assert!(receiver.open(&sender.seal(packet.clone())).is_ok());
+        assert!(receiver.open(&sender.seal(packet)).is_ok());
+    }
+
+    #[test]
+    fn forged_packets_are_rejected() {
+        let mut sealed = auth().seal(DiscoveryPacket::encode_probe());
+        let other_secret =
+            PacketAuth::new(Some(b"other".to_vec())).seal(DiscoveryPacket::encode_probe());
+        assert_eq!(
+            auth().open(&other_secret),
+            Err(String::from("Bad signature"))
+        );
+        sealed[0] ^= 1;
+        assert_eq!(auth().open(&sealed), Err(String::from("Bad signature")));
+    }
+
+    #[test]
+    fn stale_timestamps_are_rejected() {
+        let skew = MAX_CLOCK_SKEW.as_millis() as u64 + 1000;
+        for timestamp in [now_millis() - skew, now_millis() + skew] {
+            let sealed = auth().seal_at(DiscoveryPacket::encode_probe(), timestamp, 1);
+            assert_eq!(auth().open(&sealed), Err(String::from("Stale timestamp")));
+        }
+    }
+
+    #[test]
+    fn replayed_nonces_are_rejected() {
+        let mut receiver = auth();
+        let sealed = auth().seal(DiscoveryPacket::encode_probe());
+        assert!(receiver.open(&sealed).is_ok());
+        assert_eq!(receiver.open(&sealed), Err(String::from("Replayed packet")));
+    }
+
+    #[test]
+    fn truncated_and_unsigned_packets_are_rejected() {
+        let sealed = auth().seal(DiscoveryPacket::encode_probe());
+        let truncated = &sealed[..sealed.len() - 1];
+        assert!(auth().open(truncated).is_err());
+        let no_trailer = &sealed[..HEADER_LEN];
+        assert_eq!(
+            auth().open(no_trailer),
+            Err(String::from("Packet too short"))
+        );
+        assert_eq!(
+            auth().open(&DiscoveryPacket::encode_probe()),
+            Err(String::from("Unsigned packet"))
+        );
+    }
+
+    #[test]
+    fn without_a_secret_trailers_are_stripped() {
+        let packet = DiscoveryPacket::encode_probe();
+        let sealed = auth().seal(packet.clone());
+        let mut open = PacketAuth::new(None);
+        assert_eq!(open.open(&sealed).unwrap().len(), packet.len());
+        assert_eq!(open.open(&packet).unwrap(), &packet[..]);
+    }
+}
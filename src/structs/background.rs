@@ -0,0 +1,35 @@
+use std::time::Instant;
+
+use tokio::{sync::watch, task::JoinHandle};
+
+// A task running alongside the REPL, stopped through its shutdown signal
+pub struct BackgroundTask {
+    shutdown_tx: watch::Sender<bool>,
+    handle: JoinHandle<()>,
+    pub started: Instant,
+}
+
+impl BackgroundTask {
+    // `spawn` gets the shutdown receiver and returns the task handle
+    pub fn start<F>(spawn: F) -> BackgroundTask
+    where
+        F: FnOnce(watch::Receiver<bool>) -> JoinHandle<()>,
+    {
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        BackgroundTask {
+            shutdown_tx,
+            handle: spawn(shutdown_rx),
+            started: Instant::now(),
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        !self.handle.is_finished()
+    }
+
+    // Signal the task and wait for it to wind down
+    pub async fn stop(self) {
+        let _ = self.shutdown_tx.send(true);
+        let _ = self.handle.await;
+    }
+}
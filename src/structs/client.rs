@@ -1,144 +1,358 @@
 use core::fmt;
 use std::{
-    collections::HashMap,
-    net::{IpAddr, Ipv4Addr, SocketAddr},
+    net::SocketAddr,
+    path::{Path, PathBuf},
     sync::Arc,
+    time::Duration,
 };
 
 use tokio::{
-    io::{AsyncBufReadExt, BufReader},
-    net::{TcpSocket, UdpSocket},
+    io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpSocket, UdpSocket, lookup_host},
     select,
-    sync::{RwLock, watch},
+    sync::{Mutex, Notify, RwLock},
     task,
+    time::{Instant, interval, interval_at, timeout},
 };
 
-use crate::global::helper::quit_task_handler;
+use crate::global::{
+    config::{Config, DiscoveryMode},
+    helper::{
+        bind_addr, bind_shared_udp, join_discovery_group, parse_scoped_ip, scoped_socket_addr,
+    },
+};
 
 use super::{
+    auth::PacketAuth,
+    background::BackgroundTask,
     command::{Command, CommandType},
-    discovery::DiscoveryMessage,
+    discovery::DiscoveryPacket,
+    host_table::{HostOrder, HostTable},
+    protocol::{ChatMessage, FrameReader, write_message},
     user::UserTrait,
 };
 
+// How often a running discovery writes the known hosts cache
+const CACHE_SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+// The host must let us in or refuse us within this time, as long as it waits for our hello
+const WELCOME_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How often every live host is pinged for its round trip time
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+
 pub struct Client {
     name: String,
-    hosts: Arc<RwLock<HashMap<String, DiscoveryMessage>>>,
+    // Sent in the handshake, hosts without a password ignore it
+    password: Option<String>,
+    discovery: DiscoveryMode,
+    secret: Option<Vec<u8>>,
+    hosts: Arc<RwLock<HostTable>>,
+    hosts_file: PathBuf,
+    // Seed peers from the config, resolved when discovery first starts
+    seed_peers: Vec<String>,
+    // Hosts probed by unicast, the discovery task probes again when notified
+    peers: Arc<Mutex<Vec<SocketAddr>>>,
+    peers_changed: Arc<Notify>,
+    // Peers are probed often enough to stay within the ttl
+    peer_probe_interval: Duration,
+    discovery_task: Option<BackgroundTask>,
 }
 
 impl Client {
-    pub fn new(name: String) -> Client {
+    pub fn new(config: &Config) -> Client {
+        let mut hosts = HostTable::new(config.host_ttl, config.search_groups.clone());
+        match hosts.load(&config.hosts_file) {
+            Ok(0) => {}
+            Ok(n) => println!("> Loaded {n} cached host(s), stale until they announce again"),
+            Err(e) => eprintln!(
+                "Failed to load cached hosts from {}, Error: {e}",
+                config.hosts_file.display()
+            ),
+        }
         Client {
-            name,
-            hosts: Arc::new(RwLock::new(HashMap::new())),
+            name: config.name.clone(),
+            password: config.password.clone(),
+            discovery: config.discovery,
+            secret: config.secret.clone(),
+            hosts: Arc::new(RwLock::new(hosts)),
+            hosts_file: config.hosts_file.clone(),
+            seed_peers: config.peers.clone(),
+            peers: Arc::new(Mutex::new(Vec::new())),
+            peers_changed: Arc::new(Notify::new()),
+            peer_probe_interval: (config.host_ttl / 3).max(Duration::from_secs(1)),
+            discovery_task: None,
         }
     }
 
-    pub async fn start_chat(&self, host: [u8; 4], client_port: u16, host_port: u16) {
-        let client_addr: SocketAddr =
-            SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), client_port);
-        let host_addr: SocketAddr = SocketAddr::new(
-            IpAddr::V4(Ipv4Addr::new(host[0], host[1], host[2], host[3])),
-            host_port,
-        );
-        let socket = TcpSocket::new_v4().unwrap();
-        socket.bind(client_addr).unwrap();
+    // Chat with the host until q or /quit, typed lines go to the host,
+    // lines from the host are printed as they arrive
+    pub async fn start_chat(&self, host_addr: SocketAddr, client_port: u16) {
+        let client_addr = bind_addr("", client_port, &host_addr);
+        let socket = if host_addr.is_ipv4() {
+            TcpSocket::new_v4()
+        } else {
+            TcpSocket::new_v6()
+        };
+        // Reconnecting right after leaving finds the port in TIME_WAIT
+        let socket = match socket.and_then(|s| s.set_reuseaddr(true).map(|_| s)) {
+            Ok(s) => s,
+            Err(e) => {
+                println!("Failed to create socket, Error: {e}");
+                return;
+            }
+        };
+        if let Err(e) = socket.bind(client_addr) {
+            println!("Failed to bind {client_addr}, Error: {e}");
+            return;
+        }
         let stream = match socket.connect(host_addr).await {
             Ok(s) => s,
             Err(e) => {
-                println!(
-                    "Failed in connecting to host {}.{}.{}.{}:{host_port}, Error: {e}",
-                    host[0], host[1], host[2], host[3]
-                );
+                println!("Failed in connecting to host {host_addr}, Error: {e}");
                 return;
             }
         };
 
-        let (readstream, _writestream) = stream.into_split();
-        let mut buf_reader = BufReader::new(readstream);
+        let (readstream, mut writestream) = stream.into_split();
+        let mut frames = FrameReader::new(readstream);
+        let mut stdin = BufReader::new(io::stdin()).lines();
 
-        // Setup shutdown signal
-        let (shutdown_tx, mut shutdown_rx) = watch::channel(false);
-
-        // Spawn task to read stdin and look for 'q'
-        let quit_task = quit_task_handler(shutdown_tx).await;
+        let hello = ChatMessage::Hello {
+            name: self.name.clone(),
+            password: self.password.clone().unwrap_or_default(),
+        };
+        if let Err(e) = write_message(&mut writestream, &hello).await {
+            println!("Failed to greet host {host_addr}, Error: {e}");
+            return;
+        }
+        // The host lets us in with a welcome, or says why not and closes
+        match timeout(WELCOME_TIMEOUT, frames.next()).await {
+            Err(_) => {
+                println!("Host {host_addr} did not answer");
+                return;
+            }
+            Ok(Ok(Some(ChatMessage::Error { text }))) => {
+                println!("Host {host_addr} refused to let us in: {text}");
+                return;
+            }
+            Ok(Ok(Some(welcome))) => {
+                println!(
+                    "> Connected to {host_addr} as {}, type to chat, q or /quit to leave",
+                    self.name
+                );
+                println!("> {}", welcome);
+            }
+            Ok(Ok(None)) => {
+                println!("Host {host_addr} closed the connection");
+                return;
+            }
+            Ok(Err(e)) => {
+                println!("Failed in joining host {host_addr}, Error: {e}");
+                return;
+            }
+        }
 
-        let tcp_socket = {
-            let _name = self.name.clone();
-            let mut line = String::new();
-            task::spawn(async move {
-                loop {
-                    select! {
-                        _ = shutdown_rx.changed() => {
-                            if *shutdown_rx.borrow() {
-                                break;
+        let host_left = loop {
+            select! {
+                read = frames.next() => {
+                    match read {
+                        Ok(None) => break true,
+                        Ok(Some(ChatMessage::Ping { nonce })) => {
+                            if let Err(e) = write_message(&mut writestream, &ChatMessage::Pong { nonce }).await {
+                                eprintln!("Write error: {:?}", e);
+                                break true;
                             }
                         }
-                        read = buf_reader.read_line(&mut line) => {
-                            match read {
-                                Ok(0) => {
-                                    println!("Host disconnected!");
-                                    break;
-                                }
-                                Ok(_) => {
-                                    line = line.trim().to_string();
-                                    println!("> {}",line);
-                                    line.clear();
-                                }
-                                Err(e) => {
-                                    eprintln!("Read error: {:?}", e);
-                                    break;
-                                }
-                            }
+                        Ok(Some(ChatMessage::Pong { .. })) => {}
+                        Ok(Some(message)) => println!("> {}", message),
+                        Err(e) => {
+                            eprintln!("Read error: {:?}", e);
+                            break true;
                         }
                     }
                 }
-            })
+                input = stdin.next_line() => {
+                    let text = match input {
+                        Ok(Some(text)) => text,
+                        // stdin closed
+                        Ok(None) => break false,
+                        Err(e) => {
+                            eprintln!("Failed to read stdin: {:?}", e);
+                            break false;
+                        }
+                    };
+                    let message = match text.trim() {
+                        "q" | "/quit" => break false,
+                        "/who" => ChatMessage::Who,
+                        "" => continue,
+                        text => ChatMessage::Chat {
+                            from: String::new(),
+                            text: text.to_string(),
+                        },
+                    };
+                    if let Err(e) = write_message(&mut writestream, &message).await {
+                        eprintln!("Write error: {:?}", e);
+                        break true;
+                    }
+                }
+            }
         };
 
-        println!("> Enter q then ENTER for exit discovering");
+        if host_left {
+            // A stdin read is still pending, let it take the next line instead of the prompt
+            println!("Host disconnected! Press ENTER to return to the prompt");
+            let _ = stdin.next_line().await;
+        } else {
+            let _ = writestream.shutdown().await;
+        }
+    }
 
-        // Wait for either task to finish
-        tokio::select! {
-            _ = quit_task => {}
-            _ = tcp_socket => {}
+    // Find the advertised tcp endpoint for `arg`, which is ip, ip%scope,
+    // ip:port or [ipv6]:port. Hosts not in the table fall back to `default_port`.
+    async fn resolve_host(&self, arg: &str, default_port: u16) -> Option<SocketAddr> {
+        if let Ok(addr) = arg.parse::<SocketAddr>() {
+            return Some(addr);
         }
+        let (ip, scope) = parse_scoped_ip(arg.trim_start_matches('[').trim_end_matches(']'))?;
+        let hosts = self.hosts.read().await;
+        let advertised = hosts.values().find_map(|dm| {
+            dm.endpoint()
+                .filter(|ep| ep.ip() == ip || dm.source.ip() == ip)
+        });
+        Some(advertised.unwrap_or(scoped_socket_addr(ip, default_port, scope)))
     }
 
-    pub async fn search_for_hosts(&mut self, host: String, client_port: u16) {
-        let socket = UdpSocket::bind(format!("{}:{}", host, client_port))
-            .await
-            .unwrap();
-        let mut buf = [0; 1024];
+    // Resolve ip, ip%scope, ip:port, [ipv6]:port or name[:port] to a probe address
+    async fn resolve_peer(arg: &str, default_port: u16) -> Option<SocketAddr> {
+        if let Ok(addr) = arg.parse::<SocketAddr>() {
+            return Some(addr);
+        }
+        if let Some((ip, scope)) =
+            parse_scoped_ip(arg.trim_start_matches('[').trim_end_matches(']'))
+        {
+            return Some(scoped_socket_addr(ip, default_port, scope));
+        }
+        let name = if arg.contains(':') {
+            arg.to_string()
+        } else {
+            format!("{arg}:{default_port}")
+        };
+        lookup_host(name).await.ok()?.next()
+    }
+
+    // Probe `arg` by unicast from now on, it shows up like any other host once it answers
+    async fn add_peer(&self, arg: &str, host_port: u16) {
+        let Some(addr) = Client::resolve_peer(arg, host_port).await else {
+            println!("Could not resolve host {arg}");
+            return;
+        };
+        let mut peers = self.peers.lock().await;
+        if peers.contains(&addr) {
+            println!("{addr} is already probed");
+            return;
+        }
+        peers.push(addr);
+        self.peers_changed.notify_one();
+        if self.discovery_task.as_ref().is_some_and(|t| t.is_running()) {
+            println!("> Probing {addr} by unicast");
+        } else {
+            println!(
+                "> Will probe {addr} by unicast once discovery starts, DISCOVERY START to start"
+            );
+        }
+    }
+
+    // Listen for hosts in the background, the REPL stays usable meanwhile
+    pub async fn start_discovery(&mut self, host: String, client_port: u16, host_port: u16) {
+        if self.discovery_task.as_ref().is_some_and(|t| t.is_running()) {
+            println!("Discovery is already running");
+            return;
+        }
+
+        // Shared with other listeners on this machine, every one of them gets the announcements
+        let group_addr = self.discovery.target(client_port);
+        let socket = match bind_shared_udp(bind_addr(&host, client_port, &group_addr)) {
+            Ok(s) => s,
+            Err(e) => {
+                println!("Failed to listen on port {client_port}, Error: {e}");
+                return;
+            }
+        };
+        if let Err(e) = join_discovery_group(&socket, self.discovery, &host) {
+            println!(
+                "Failed to join discovery group {}, Error: {e}",
+                group_addr.ip()
+            );
+            return;
+        }
 
-        // Setup shutdown signal
-        let (shutdown_tx, mut shutdown_rx) = watch::channel(false);
+        let mut auth = PacketAuth::new(self.secret.clone());
 
-        // Spawn task to read stdin and look for 'q'
-        let quit_task = quit_task_handler(shutdown_tx).await;
+        // Ask every host to answer right away instead of waiting for the next announce.
+        // Unicast answers to a shared port reach only one listener, so probe from our own port
+        let probe_addr = self.discovery.target(host_port);
+        let probe_socket = match UdpSocket::bind(bind_addr(&host, 0, &probe_addr)).await {
+            Ok(s) => s,
+            Err(e) => {
+                println!("Failed to bind probe socket, Error: {e}");
+                return;
+            }
+        };
+        if let DiscoveryMode::Broadcast = self.discovery {
+            let _ = probe_socket.set_broadcast(true);
+        }
+        if let Err(e) = probe_socket
+            .send_to(&auth.seal(DiscoveryPacket::encode_probe()), &probe_addr)
+            .await
+        {
+            eprintln!("Failed to send probe: {}", e);
+        }
+        let mut buf = [0; 1024];
+        let mut probe_buf = [0; 1024];
 
         // UDP Listening loop
-        let udp_task = {
-            let hosts = Arc::clone(&self.hosts);
+        let hosts = Arc::clone(&self.hosts);
+        let hosts_file = self.hosts_file.clone();
+        let peers = Arc::clone(&self.peers);
+        let peers_changed = Arc::clone(&self.peers_changed);
+        let peer_probe_interval = self.peer_probe_interval;
+        self.discovery_task = Some(BackgroundTask::start(|mut shutdown_rx| {
             task::spawn(async move {
+                let mut expiry_ticker = interval(Duration::from_secs(1));
+                let mut peer_ticker = interval(peer_probe_interval);
+                let mut ping_ticker = interval(PING_INTERVAL);
+                let mut save_ticker =
+                    interval_at(Instant::now() + CACHE_SAVE_INTERVAL, CACHE_SAVE_INTERVAL);
                 loop {
                     select! {
-                        _ = shutdown_rx.changed() => {
-                            if *shutdown_rx.borrow() {
+                        res = shutdown_rx.changed() => {
+                            if res.is_err() || *shutdown_rx.borrow() {
                                 break;
                             }
                         }
+                        _ = expiry_ticker.tick() => {
+                            for event in hosts.write().await.expire() {
+                                event.print();
+                            }
+                        }
+                        _ = save_ticker.tick() => save_cache(&*hosts.read().await, &hosts_file),
+                        // Peers get no broadcast, keep them alive by probing them directly
+                        _ = peer_ticker.tick() => probe_peers(&probe_socket, &auth, &peers).await,
+                        _ = peers_changed.notified() => probe_peers(&probe_socket, &auth, &peers).await,
+                        _ = ping_ticker.tick() => ping_hosts(&probe_socket, &auth, &hosts).await,
                         result = socket.recv_from(&mut buf) => {
                             match result {
-                                Ok((_, addr)) => {
-                                    let s: String = format!("{}:{}", addr.ip(), addr.port());
-                                    let mut hosts = hosts.write().await;
-                                    if !hosts.contains_key(&s) {
-                                        let dm = DiscoveryMessage::new(&addr);
-                                        hosts.insert(s.clone(), dm);
-                                        println!("> found host {}:{}", addr.ip(), addr.port());
-                                    }
+                                Ok((n, addr)) => record_packet(&hosts, &mut auth, &buf[..n], &addr).await,
+                                Err(e) => {
+                                    eprintln!("UDP recv error: {}", e);
+                                    break;
                                 }
+                            }
+                        }
+                        result = probe_socket.recv_from(&mut probe_buf) => {
+                            match result {
+                                Ok((n, addr)) => record_packet(&hosts, &mut auth, &probe_buf[..n], &addr).await,
                                 Err(e) => {
                                     eprintln!("UDP recv error: {}", e);
                                     break;
@@ -147,25 +361,116 @@ impl Client {
                         }
                     }
                 }
+                save_cache(&*hosts.read().await, &hosts_file);
             })
-        };
+        }));
+
+        for seed in std::mem::take(&mut self.seed_peers) {
+            self.add_peer(&seed, host_port).await;
+        }
+        println!("> Searching in the background, DISCOVERY STOP to stop");
+    }
+
+    pub async fn stop_discovery(&mut self) {
+        if let Some(t) = self.discovery_task.take() {
+            t.stop().await;
+            println!("> Discovery stopped");
+        }
+    }
 
-        println!("> Enter q then ENTER for exit discovering");
-        println!("> Searching...");
+    async fn print_discovery_status(&self) {
+        match &self.discovery_task {
+            Some(t) if t.is_running() => {
+                let hosts = self.hosts.read().await;
+                let groups = match hosts.groups() {
+                    [] => String::from("all groups"),
+                    g => g.join(", "),
+                };
+                println!(
+                    "Discovery running for {}s ({:?}), {} host(s) known, {} peer(s) probed, searching {}",
+                    t.started.elapsed().as_secs(),
+                    self.discovery,
+                    hosts.len(),
+                    self.peers.lock().await.len(),
+                    groups
+                );
+            }
+            _ => println!("Discovery stopped"),
+        }
+    }
+}
 
-        // Wait for either task to finish
-        tokio::select! {
-            _ = quit_task => {}
-            _ = udp_task => {}
+async fn probe_peers(socket: &UdpSocket, auth: &PacketAuth, peers: &Mutex<Vec<SocketAddr>>) {
+    for peer in peers.lock().await.iter() {
+        // Sealed per peer, two peers may be the same host
+        let probe = auth.seal(DiscoveryPacket::encode_probe());
+        if let Err(e) = socket.send_to(&probe, peer).await {
+            eprintln!("Failed to probe {}: {}", peer, e);
         }
+    }
+}
+
+// Hosts answer on the ping port they announce, pongs come back to the probe socket
+async fn ping_hosts(socket: &UdpSocket, auth: &PacketAuth, hosts: &RwLock<HostTable>) {
+    let family_v4 = socket.local_addr().is_ok_and(|a| a.is_ipv4());
+    let pings = hosts
+        .write()
+        .await
+        .start_pings(|addr| addr.is_ipv4() == family_v4);
+    for (nonce, addr) in pings {
+        if let Err(e) = socket
+            .send_to(&auth.seal(DiscoveryPacket::encode_ping(nonce)), addr)
+            .await
+        {
+            eprintln!("Failed to ping {}: {}", addr, e);
+        }
+    }
+}
 
-        // Ok(())
+fn save_cache(hosts: &HostTable, path: &Path) {
+    if let Err(e) = hosts.save(path) {
+        eprintln!(
+            "Failed to save known hosts to {}, Error: {e}",
+            path.display()
+        );
+    }
+}
+
+// Apply one received discovery packet to the host table
+async fn record_packet(
+    hosts: &RwLock<HostTable>,
+    auth: &mut PacketAuth,
+    buf: &[u8],
+    addr: &SocketAddr,
+) {
+    let body = match auth.open(buf) {
+        Ok(body) => body,
+        Err(e) => {
+            eprintln!("> rejected packet from {}: {}", addr, e);
+            return;
+        }
+    };
+    let event = match DiscoveryPacket::decode(body, addr) {
+        Ok(DiscoveryPacket::Announce(dm)) => hosts.write().await.upsert(dm),
+        Ok(DiscoveryPacket::Goodbye { host_id, .. }) => hosts.write().await.remove_by_id(&host_id),
+        Ok(DiscoveryPacket::Pong { nonce }) => {
+            hosts.write().await.record_pong(nonce);
+            None
+        }
+        Ok(DiscoveryPacket::Probe | DiscoveryPacket::Ping { .. }) => None,
+        Err(e) => {
+            eprintln!("> ignored packet from {}: {}", addr, e);
+            None
+        }
+    };
+    if let Some(event) = event {
+        event.print();
     }
 }
 
 impl fmt::Display for Client {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", "Client")
+        write!(f, "Client")
     }
 }
 
@@ -173,42 +478,54 @@ impl UserTrait for Client {
     async fn execute_command(
         &mut self,
         cmd: &Command,
-        _host: &String,
+        host: &str,
         client_port: &u16,
         host_port: &u16,
     ) -> Result<(), String> {
         match &cmd.command_type {
             Some(ty) => match ty {
+                CommandType::DiscoveryStart => {
+                    self.start_discovery(host.to_string(), *client_port, *host_port)
+                        .await;
+                }
+                CommandType::DiscoveryStop => self.stop_discovery().await,
+                CommandType::DiscoveryStatus => self.print_discovery_status().await,
+                CommandType::AddHost => match cmd.args.get(1) {
+                    Some(arg) => self.add_peer(arg, *host_port).await,
+                    None => println!("Host address required => ADD HOST <ip>[:port]"),
+                },
                 CommandType::ListHosts => {
-                    let hosts = self.hosts.read().await;
+                    let mut hosts = self.hosts.write().await;
+                    for event in hosts.expire() {
+                        event.print();
+                    }
                     if hosts.is_empty() {
                         println!("No host found!")
                     } else {
-                        let mut i = 1;
-                        for (host, _) in hosts.iter() {
-                            println!("[{}] {}", i, host);
-                            i = i + 1;
-                        }
+                        let order = match cmd.args.get(1).map(|a| a.to_uppercase()).as_deref() {
+                            Some("LATENCY" | "RTT") => HostOrder::Latency,
+                            _ => HostOrder::Name,
+                        };
+                        hosts.print_table(order);
                     }
                 }
-                CommandType::Start => {
-                    if cmd.args.len() == 0 {
-                        println!("Host server address required => START <ip>")
+                CommandType::Search => {
+                    let mut hosts = self.hosts.write().await;
+                    hosts.set_groups(cmd.args.clone());
+                    if hosts.groups().is_empty() {
+                        println!("Searching all groups")
                     } else {
-                        let ip: Vec<u8> = cmd.args[0]
-                            .clone()
-                            .split('.')
-                            .filter_map(|s| s.parse::<u8>().ok())
-                            .collect();
-                        if ip.len() != 4 {
-                            println!("Wrong ip address provided");
+                        println!("Searching groups: {}", hosts.groups().join(", "))
+                    }
+                }
+                CommandType::Start | CommandType::Connect => {
+                    if cmd.args.is_empty() {
+                        println!("Host server address required => START <ip>[:port]")
+                    } else {
+                        match self.resolve_host(&cmd.args[0], *host_port).await {
+                            Some(addr) => self.start_chat(addr, *client_port).await,
+                            None => println!("Wrong ip address provided"),
                         }
-                        self.start_chat(
-                            [ip[0], ip[1], ip[2], ip[3]],
-                            client_port.clone(),
-                            host_port.clone(),
-                        )
-                        .await;
                     }
                 }
                 _ => {
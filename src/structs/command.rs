@@ -1,16 +1,26 @@
 #[derive(Debug)]
 pub enum CommandType {
-    Help,         // For listing all commands
-    Exit,         // Exit the program
-    Clear,        // Clear the screen
-    BecomeHost,   // Start sending UDP discovery packets
-    BecomeClient, // Bind to default port for host discovery, and save host details in memory
-    ListHosts,    // List all hosts in memory
-    Start,        // Start TCP server for accepting connection from client
-    Connect,      // Connect to a host
-    Disconnect,   // Disconnect from a host
-    _Send,        // Start message sending session
-    _Receive,     // Start message receiving session
+    Help,            // For listing all commands
+    Exit,            // Exit the program
+    Clear,           // Clear the screen
+    BecomeHost,      // Start sending UDP discovery packets
+    BecomeClient,    // Bind to default port for host discovery, and save host details in memory
+    ListHosts,       // List all hosts in memory
+    AddHost,         // Probe a host by unicast, for networks that drop broadcast
+    Search,          // Only record hosts of the given discovery groups
+    DiscoveryStart,  // Start background discovery (client listens, host announces)
+    DiscoveryStop,   // Stop background discovery
+    DiscoveryStatus, // Show whether background discovery is running
+    Start,           // Start TCP server for accepting connection from client
+    Stop,            // Stop the TCP server, and the announcements with it
+    Name,            // Rename the host, announced right away
+    Topic,           // Change the topic of the room, announced right away
+    Connect,         // Connect to a host
+    Disconnect,      // Disconnect from a host
+    Chat,            // Host chats in its own room until q or /quit
+    Who,             // List the members of the host's room
+    Kick,            // Throw a client out of the host's room
+    _Receive,        // Start message receiving session
 }
 
 #[derive(Debug)]
@@ -20,7 +30,7 @@ pub struct Command {
 }
 
 impl Command {
-    pub fn marshal(&mut self, s: &String) -> Result<(), String> {
+    pub fn marshal(&mut self, s: &str) -> Result<(), String> {
         let args = s.split_whitespace().collect::<Vec<&str>>();
         if args.is_empty() {
             return Err(String::from("No command provided"));
@@ -51,9 +61,38 @@ impl Command {
                     }
                 }
             }
+            "ADD" => {
+                if args.len() < 2 {
+                    Some(CommandType::Help)
+                } else {
+                    match args[1].to_uppercase().as_str() {
+                        "HOST" => Some(CommandType::AddHost),
+                        _ => Some(CommandType::Help),
+                    }
+                }
+            }
+            "SEARCH" => Some(CommandType::Search),
+            "DISCOVERY" => {
+                if args.len() < 2 {
+                    Some(CommandType::DiscoveryStatus)
+                } else {
+                    match args[1].to_uppercase().as_str() {
+                        "START" => Some(CommandType::DiscoveryStart),
+                        "STOP" => Some(CommandType::DiscoveryStop),
+                        "STATUS" => Some(CommandType::DiscoveryStatus),
+                        _ => Some(CommandType::Help),
+                    }
+                }
+            }
             "CONNECT" => Some(CommandType::Connect),
             "DISCONNECT" => Some(CommandType::Disconnect),
             "START" => Some(CommandType::Start),
+            "STOP" => Some(CommandType::Stop),
+            "NAME" => Some(CommandType::Name),
+            "TOPIC" => Some(CommandType::Topic),
+            "CHAT" => Some(CommandType::Chat),
+            "WHO" | "/WHO" => Some(CommandType::Who),
+            "KICK" => Some(CommandType::Kick),
             _ => Some(CommandType::Help),
         };
 
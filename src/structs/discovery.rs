@@ -1,16 +1,616 @@
-use std::net::SocketAddr;
+use std::{
+    fs, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::Path,
+};
 
-#[derive(Hash, PartialEq, Eq, Debug)]
+use crate::global::helper::random_u64;
+
+// Every discovery packet starts with these bytes, anything else is ignored
+pub const MAGIC: &[u8; 4] = b"UDPD";
+pub const PROTOCOL_VERSION: u8 = 2;
+
+// Header is magic, version, kind and flags
+pub const HEADER_LEN: usize = 7;
+pub const FLAGS_OFFSET: usize = 6;
+pub const FLAG_SIGNED: u8 = 0b0000_0001;
+
+const KIND_ANNOUNCE: u8 = 1;
+const KIND_GOODBYE: u8 = 2;
+const KIND_PROBE: u8 = 3;
+const KIND_PING: u8 = 4;
+const KIND_PONG: u8 = 5;
+
+// Optional chat protocol features a host supports
+pub const FEATURE_ENCRYPTION: u8 = 0b0000_0001;
+pub const FEATURE_COMPRESSION: u8 = 0b0000_0010;
+
+const FAMILY_V4: u8 = 4;
+const FAMILY_V6: u8 = 6;
+
+/*
+ * Discovery packet layout (all integers big endian)
+ *
+ * header   => | magic "UDPD" | version u8 | kind u8 | flags u8 |
+ * announce => | header kind 1 | host id str | name str | room str | group str | endpoints |
+ *             | occupancy u16 | capacity u16 | password u8 | features u8 | topic str | path |
+ *             | ping port u16 |
+ * goodbye  => | header kind 2 | host id str | path |
+ * probe    => | header kind 3 |
+ * ping     => | header kind 4 | nonce u64 |
+ * pong     => | header kind 5 | nonce u64 |
+ *
+ * Packets with FLAG_SIGNED carry an auth trailer, see `auth.rs`.
+ *
+ * str       => u8 length followed by that many UTF-8 bytes
+ * endpoints => u8 count followed by count * (family u8 | 4 or 16 ip bytes | port u16)
+ * path      => hops u8 | u8 count followed by count * relay id str
+ *
+ * Group is the discovery namespace of the host, clients only record the groups they search for.
+ * Endpoints are the TCP addresses the chat server listens on, an unspecified
+ * ip (0.0.0.0 / ::) means "the address this packet came from".
+ * Occupancy is the number of connected clients, capacity 0 means unlimited.
+ * Password is 1 when joining needs a password, features is a FEATURE_* bit set.
+ * Path is empty when sent by the host, every relay adds a hop and its id.
+ * Ping port is the UDP port of the first endpoint's address that answers pings, 0 for none.
+ * It is a socket of its own, the shared discovery port would hand a unicast ping to any
+ * process listening on it.
+ *
+ * Version 1 was | header kind 1 | tcp port u16 | host id str | name str | room str |.
+ * Version 2 is the layout above: flags and the auth trailer, the endpoint list instead of
+ * the tcp port, group, occupancy, capacity, password, features, topic, relay path and ping
+ * port in the announce, and the goodbye, probe, ping and pong kinds. Peers on version 1
+ * are ignored, there is no fallback.
+ */
+pub enum DiscoveryPacket {
+    Announce(DiscoveryMessage),
+    Goodbye { host_id: String, path: RelayPath },
+    // Sent by a client, every host answers with a unicast announce
+    Probe,
+    // Round trip time measurement, the host echoes the nonce in a pong
+    Ping { nonce: u64 },
+    Pong { nonce: u64 },
+}
+
+// Relays a packet went through, used to stop it from looping between subnets
+#[derive(Hash, PartialEq, Eq, Debug, Clone, Default)]
+pub struct RelayPath {
+    pub hops: u8,
+    pub relays: Vec<String>,
+}
+
+impl RelayPath {
+    // Path after passing relay `id`
+    pub fn through(&self, id: &str) -> RelayPath {
+        let mut relays = self.relays.clone();
+        relays.push(id.to_string());
+        RelayPath {
+            hops: self.hops.saturating_add(1),
+            relays,
+        }
+    }
+}
+
+#[derive(Hash, PartialEq, Eq, Debug, Clone)]
 pub struct DiscoveryMessage {
-    pub ip: String,
-    pub port: u16,
+    pub source: SocketAddr,
+    pub version: u8,
+    pub host_id: String,
+    pub name: String,
+    pub room: String,
+    pub group: String,
+    pub endpoints: Vec<SocketAddr>,
+    pub occupancy: u16,
+    pub capacity: u16,
+    pub password: bool,
+    pub features: u8,
+    pub topic: String,
+    pub path: RelayPath,
+    pub ping_port: u16,
 }
 
 impl DiscoveryMessage {
-    pub fn new(addr: &SocketAddr) -> DiscoveryMessage {
+    pub fn new(
+        host_id: String,
+        name: String,
+        room: String,
+        group: String,
+        endpoints: Vec<SocketAddr>,
+    ) -> DiscoveryMessage {
         DiscoveryMessage {
-            ip: addr.ip().to_string(),
-            port: addr.port(),
+            source: SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+            version: PROTOCOL_VERSION,
+            host_id,
+            name,
+            room,
+            group,
+            endpoints,
+            occupancy: 0,
+            capacity: 0,
+            password: false,
+            features: 0,
+            topic: String::new(),
+            path: RelayPath::default(),
+            ping_port: 0,
+        }
+    }
+
+    // Announcement of host `id` in `group` serving at `endpoint`
+    #[cfg(test)]
+    pub fn test(id: &str, group: &str, endpoint: &str) -> DiscoveryMessage {
+        DiscoveryMessage::new(
+            String::from(id),
+            format!("host-{id}"),
+            String::from("general"),
+            String::from(group),
+            vec![endpoint.parse().unwrap()],
+        )
+    }
+
+    // Preferred TCP address for connecting to the chat server, None before the host
+    // started serving. Received announcements always have one
+    pub fn endpoint(&self) -> Option<SocketAddr> {
+        self.endpoints.first().copied()
+    }
+
+    // UDP address the host answers pings on
+    pub fn ping_addr(&self) -> Option<SocketAddr> {
+        let mut addr = self.endpoint().filter(|_| self.ping_port != 0)?;
+        addr.set_port(self.ping_port);
+        Some(addr)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(64);
+        put_header(&mut buf, KIND_ANNOUNCE);
+        put_str(&mut buf, &self.host_id);
+        put_str(&mut buf, &self.name);
+        put_str(&mut buf, &self.room);
+        put_str(&mut buf, &self.group);
+        let count = self.endpoints.len().min(u8::MAX as usize);
+        buf.push(count as u8);
+        for ep in &self.endpoints[..count] {
+            put_addr(&mut buf, ep);
+        }
+        buf.extend_from_slice(&self.occupancy.to_be_bytes());
+        buf.extend_from_slice(&self.capacity.to_be_bytes());
+        buf.push(self.password as u8);
+        buf.push(self.features);
+        put_str(&mut buf, &self.topic);
+        put_path(&mut buf, &self.path);
+        buf.extend_from_slice(&self.ping_port.to_be_bytes());
+        buf
+    }
+
+    // Departure notice, clients drop the host as soon as they get it
+    pub fn encode_goodbye(&self) -> Vec<u8> {
+        DiscoveryPacket::encode_goodbye(&self.host_id, &self.path)
+    }
+}
+
+impl DiscoveryPacket {
+    // Parse a received datagram without its auth trailer, `addr` is the source of the packet
+    pub fn decode(buf: &[u8], addr: &SocketAddr) -> Result<DiscoveryPacket, String> {
+        let mut r = Reader { buf, pos: 0 };
+
+        if r.take(MAGIC.len())? != MAGIC {
+            return Err(String::from("Bad magic"));
+        }
+        let version = r.u8()?;
+        if version != PROTOCOL_VERSION {
+            return Err(format!("Unsupported protocol version {}", version));
+        }
+        let kind = r.u8()?;
+        let _flags = r.u8()?;
+        let packet = match kind {
+            KIND_ANNOUNCE => {
+                let host_id = decode_host_id(&mut r)?;
+                DiscoveryPacket::Announce(decode_announce(&mut r, addr, host_id)?)
+            }
+            KIND_GOODBYE => DiscoveryPacket::Goodbye {
+                host_id: decode_host_id(&mut r)?,
+                path: r.path()?,
+            },
+            KIND_PROBE => DiscoveryPacket::Probe,
+            KIND_PING => DiscoveryPacket::Ping { nonce: r.u64()? },
+            KIND_PONG => DiscoveryPacket::Pong { nonce: r.u64()? },
+            kind => return Err(format!("Unknown packet kind {}", kind)),
+        };
+        if r.pos != buf.len() {
+            return Err(String::from("Trailing bytes after packet"));
         }
+        Ok(packet)
+    }
+
+    pub fn encode_goodbye(host_id: &str, path: &RelayPath) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(32);
+        put_header(&mut buf, KIND_GOODBYE);
+        put_str(&mut buf, host_id);
+        put_path(&mut buf, path);
+        buf
+    }
+
+    pub fn encode_probe() -> Vec<u8> {
+        let mut buf = Vec::with_capacity(8);
+        put_header(&mut buf, KIND_PROBE);
+        buf
+    }
+
+    pub fn encode_ping(nonce: u64) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(16);
+        put_header(&mut buf, KIND_PING);
+        buf.extend_from_slice(&nonce.to_be_bytes());
+        buf
+    }
+
+    pub fn encode_pong(nonce: u64) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(16);
+        put_header(&mut buf, KIND_PONG);
+        buf.extend_from_slice(&nonce.to_be_bytes());
+        buf
+    }
+}
+
+fn decode_host_id(r: &mut Reader) -> Result<String, String> {
+    let host_id = r.str()?;
+    if host_id.is_empty() {
+        return Err(String::from("Empty host id"));
+    }
+    Ok(host_id)
+}
+
+fn decode_announce(
+    r: &mut Reader,
+    addr: &SocketAddr,
+    host_id: String,
+) -> Result<DiscoveryMessage, String> {
+    let name = r.str()?;
+    let room = r.str()?;
+    let group = r.str()?;
+    let count = r.u8()?;
+    if count == 0 {
+        return Err(String::from("No tcp endpoint advertised"));
+    }
+    let mut endpoints = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let mut ep = r.addr()?;
+        if ep.port() == 0 {
+            return Err(String::from("Invalid tcp port 0"));
+        }
+        if ep.ip().is_unspecified() {
+            ep.set_ip(addr.ip());
+        }
+        // Link-local endpoints are only reachable through the interface the packet came in on
+        if let (SocketAddr::V6(ep), SocketAddr::V6(src)) = (&mut ep, addr)
+            && ep.scope_id() == 0
+            && ep.ip().is_unicast_link_local()
+        {
+            ep.set_scope_id(src.scope_id());
+        }
+        endpoints.push(ep);
+    }
+    let occupancy = r.u16()?;
+    let capacity = r.u16()?;
+    let password = r.u8()? != 0;
+    let features = r.u8()?;
+    let topic = r.str()?;
+    let path = r.path()?;
+    let ping_port = r.u16()?;
+
+    Ok(DiscoveryMessage {
+        source: *addr,
+        version: PROTOCOL_VERSION,
+        host_id,
+        name,
+        room,
+        group,
+        endpoints,
+        occupancy,
+        capacity,
+        password,
+        features,
+        topic,
+        path,
+        ping_port,
+    })
+}
+
+// Comma separated names of the FEATURE_* bits, "-" for none
+pub fn feature_names(features: u8) -> String {
+    let names: Vec<&str> = [
+        (FEATURE_ENCRYPTION, "encryption"),
+        (FEATURE_COMPRESSION, "compression"),
+    ]
+    .into_iter()
+    .filter(|(bit, _)| features & bit != 0)
+    .map(|(_, name)| name)
+    .collect();
+    if names.is_empty() {
+        String::from("-")
+    } else {
+        names.join(",")
+    }
+}
+
+// Generate a random looking id for a host
+pub fn generate_host_id() -> String {
+    format!("{:016x}", random_u64())
+}
+
+// Read the host id stored at `path`, creating it on first run so the
+// host keeps its identity across restarts and address changes
+pub fn load_or_create_host_id(path: &Path) -> io::Result<String> {
+    if let Ok(id) = fs::read_to_string(path) {
+        let id = id.trim();
+        if !id.is_empty() {
+            return Ok(id.to_string());
+        }
+    }
+    let id = generate_host_id();
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, format!("{}\n", id))?;
+    Ok(id)
+}
+
+fn put_header(buf: &mut Vec<u8>, kind: u8) {
+    buf.extend_from_slice(MAGIC);
+    buf.push(PROTOCOL_VERSION);
+    buf.push(kind);
+    buf.push(0);
+}
+
+fn put_str(buf: &mut Vec<u8>, s: &str) {
+    // Strings longer than 255 bytes are truncated on a char boundary
+    let mut end = s.len().min(u8::MAX as usize);
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    buf.push(end as u8);
+    buf.extend_from_slice(&s.as_bytes()[..end]);
+}
+
+fn put_addr(buf: &mut Vec<u8>, addr: &SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            buf.push(FAMILY_V4);
+            buf.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            buf.push(FAMILY_V6);
+            buf.extend_from_slice(&ip.octets());
+        }
+    }
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+fn put_path(buf: &mut Vec<u8>, path: &RelayPath) {
+    buf.push(path.hops);
+    let count = path.relays.len().min(u8::MAX as usize);
+    buf.push(count as u8);
+    for id in &path.relays[..count] {
+        put_str(buf, id);
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if self.buf.len() - self.pos < n {
+            return Err(String::from("Packet too short"));
+        }
+        let s = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(s)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        let b = self.take(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn str(&mut self) -> Result<String, String> {
+        let len = self.u8()? as usize;
+        let b = self.take(len)?;
+        String::from_utf8(b.to_vec()).map_err(|_| String::from("Invalid UTF-8 in packet"))
+    }
+
+    fn path(&mut self) -> Result<RelayPath, String> {
+        let hops = self.u8()?;
+        let count = self.u8()?;
+        let relays = (0..count)
+            .map(|_| self.str())
+            .collect::<Result<Vec<String>, String>>()?;
+        Ok(RelayPath { hops, relays })
+    }
+
+    fn addr(&mut self) -> Result<SocketAddr, String> {
+        let ip = match self.u8()? {
+            FAMILY_V4 => {
+                let b: [u8; 4] = self.take(4)?.try_into().unwrap();
+                IpAddr::V4(Ipv4Addr::from(b))
+            }
+            FAMILY_V6 => {
+                let b: [u8; 16] = self.take(16)?.try_into().unwrap();
+                IpAddr::V6(Ipv6Addr::from(b))
+            }
+            f => return Err(format!("Unknown address family {}", f)),
+        };
+        Ok(SocketAddr::new(ip, self.u16()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source() -> SocketAddr {
+        "192.0.2.7:40000".parse().unwrap()
+    }
+
+    fn announce() -> DiscoveryMessage {
+        let mut dm = DiscoveryMessage::test("0123456789abcdef", "team", "192.0.2.7:4600");
+        dm.endpoints.push("[2001:db8::7]:4600".parse().unwrap());
+        dm.occupancy = 3;
+        dm.capacity = 10;
+        dm.password = true;
+        dm.features = FEATURE_COMPRESSION;
+        dm.topic = String::from("rust");
+        dm.path = RelayPath::default().through("relay1");
+        dm.ping_port = 41000;
+        dm
+    }
+
+    fn decode(buf: &[u8]) -> Result<DiscoveryPacket, String> {
+        DiscoveryPacket::decode(buf, &source())
+    }
+
+    #[test]
+    fn announce_round_trips() {
+        let mut sent = announce();
+        sent.source = source();
+        match decode(&sent.encode()) {
+            Ok(DiscoveryPacket::Announce(dm)) => assert_eq!(dm, sent),
+            _ => panic!("announce did not decode"),
+        }
+    }
+
+    #[test]
+    fn small_packets_round_trip() {
+        let path = RelayPath::default().through("r");
+        match decode(&DiscoveryPacket::encode_goodbye("abc", &path)) {
+            Ok(DiscoveryPacket::Goodbye { host_id, path: p }) => {
+                assert_eq!(host_id, "abc");
+                assert_eq!(p, path);
+            }
+            _ => panic!("goodbye did not decode"),
+        }
+        assert!(matches!(
+            decode(&DiscoveryPacket::encode_probe()),
+            Ok(DiscoveryPacket::Probe)
+        ));
+        assert!(matches!(
+            decode(&DiscoveryPacket::encode_ping(7)),
+            Ok(DiscoveryPacket::Ping { nonce: 7 })
+        ));
+        assert!(matches!(
+            decode(&DiscoveryPacket::encode_pong(u64::MAX)),
+            Ok(DiscoveryPacket::Pong { nonce: u64::MAX })
+        ));
+    }
+
+    #[test]
+    fn unspecified_endpoints_take_the_source_address() {
+        let mut dm = announce();
+        dm.endpoints = vec!["0.0.0.0:4600".parse().unwrap()];
+        match decode(&dm.encode()) {
+            Ok(DiscoveryPacket::Announce(dm)) => {
+                assert_eq!(dm.endpoint(), Some("192.0.2.7:4600".parse().unwrap()))
+            }
+            _ => panic!("announce did not decode"),
+        }
+    }
+
+    #[test]
+    fn bad_headers_are_rejected() {
+        let mut packet = DiscoveryPacket::encode_probe();
+        packet[0] = b'X';
+        assert_eq!(decode(&packet).err(), Some(String::from("Bad magic")));
+
+        let mut packet = DiscoveryPacket::encode_probe();
+        packet[MAGIC.len()] = PROTOCOL_VERSION + 1;
+        assert!(decode(&packet).is_err());
+
+        let mut packet = DiscoveryPacket::encode_probe();
+        packet[MAGIC.len() + 1] = 99;
+        assert_eq!(
+            decode(&packet).err(),
+            Some(String::from("Unknown packet kind 99"))
+        );
+
+        assert!(decode(&packet[..HEADER_LEN - 1]).is_err());
+        assert!(decode(&[]).is_err());
+    }
+
+    #[test]
+    fn truncated_packets_are_rejected() {
+        let packet = announce().encode();
+        for len in HEADER_LEN..packet.len() {
+            assert!(decode(&packet[..len]).is_err(), "accepted {} bytes", len);
+        }
+        let mut trailing = packet.clone();
+        trailing.push(0);
+        assert!(decode(&trailing).is_err());
+    }
+
+    #[test]
+    fn bad_announce_fields_are_rejected() {
+        let mut dm = announce();
+        dm.endpoints.clear();
+        assert_eq!(
+            decode(&dm.encode()).err(),
+            Some(String::from("No tcp endpoint advertised"))
+        );
+
+        let mut dm = announce();
+        dm.endpoints = vec!["192.0.2.7:0".parse().unwrap()];
+        assert!(decode(&dm.encode()).is_err());
+
+        let mut dm = announce();
+        dm.host_id.clear();
+        assert_eq!(
+            decode(&dm.encode()).err(),
+            Some(String::from("Empty host id"))
+        );
+
+        // Family byte of the first endpoint, after the header, four strings and the count
+        let dm = announce();
+        let mut packet = dm.encode();
+        let family = HEADER_LEN
+            + [&dm.host_id, &dm.name, &dm.room, &dm.group]
+                .iter()
+                .map(|s| 1 + s.len())
+                .sum::<usize>()
+            + 1;
+        assert_eq!(packet[family], FAMILY_V4);
+        packet[family] = 5;
+        assert_eq!(
+            decode(&packet).err(),
+            Some(String::from("Unknown address family 5"))
+        );
+
+        // Invalid UTF-8 in the name
+        let mut packet = dm.encode();
+        packet[HEADER_LEN + 1 + dm.host_id.len() + 1] = 0xff;
+        assert!(decode(&packet).is_err());
+    }
+
+    #[test]
+    fn endpoint_is_none_before_serving() {
+        let mut dm = DiscoveryMessage::test("id", "g", "192.0.2.7:4600");
+        dm.endpoints.clear();
+        assert_eq!(dm.endpoint(), None);
+        assert_eq!(dm.ping_addr(), None);
+    }
+
+    #[test]
+    fn pings_go_to_the_ping_port_of_the_endpoint() {
+        let mut dm = announce();
+        assert_eq!(dm.ping_addr(), Some("192.0.2.7:41000".parse().unwrap()));
+        dm.ping_port = 0;
+        assert_eq!(dm.ping_addr(), None);
     }
 }
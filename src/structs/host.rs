@@ -1,142 +1,519 @@
 use core::fmt;
-use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    io::{self, AsyncBufReadExt, BufReader},
     net::{TcpListener, TcpStream, UdpSocket},
-    select,
-    sync::{Mutex, mpsc, watch},
+    pin, select,
+    sync::{
+        Mutex, Notify,
+        mpsc::{self, error::TrySendError},
+        watch,
+    },
     task,
-    time::interval,
+    time::{Instant, interval, interval_at, sleep, timeout},
 };
 
-use crate::global::helper::quit_task_handler;
+use crate::global::{
+    config::{Config, DiscoveryMode},
+    helper::{
+        bind_addr, bind_shared_udp, join_discovery_group, parse_scoped_ip, random_u64,
+        scoped_socket_addr,
+    },
+};
 
-use super::{command::CommandType, user::UserTrait};
+use super::{
+    announcer::{AnnounceSchedule, Announcer},
+    auth::PacketAuth,
+    background::BackgroundTask,
+    command::CommandType,
+    discovery::{DiscoveryMessage, DiscoveryPacket, generate_host_id, load_or_create_host_id},
+    protocol::{ChatMessage, FrameReader, LeaveReason, MemberInfo, write_message},
+    user::UserTrait,
+};
 
-type HostClientMap = Arc<Mutex<HashMap<SocketAddr, mpsc::Sender<Message>>>>;
+// Optional chat features this host supports, see FEATURE_* in discovery.rs
+const FEATURES: u8 = 0;
 
-struct Message {
-    producer: String,
-    message: String,
+// A client that passed the handshake
+struct Member {
+    name: String,
+    joined: Instant,
+    tx: mpsc::Sender<ChatMessage>,
+    // Notified to throw the client out
+    kick: Arc<Notify>,
 }
 
+type HostClientMap = Arc<Mutex<HashMap<SocketAddr, Member>>>;
+
+// Current announcement, every change to it is announced right away
+type Announcement = Arc<watch::Sender<DiscoveryMessage>>;
+
+// How often the announcer looks for changed interface addresses
+const ADDRESS_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+// A new connection must say hello within this time
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How often an idle client is pinged
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+// A client not heard from for this long, pongs included, has timed out
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(45);
+
+// Longest nickname a client may use, in characters
+const MAX_NAME_LEN: usize = 32;
+
 pub struct Host {
+    id: String,
     name: String,
+    discovery: DiscoveryMode,
+    schedule: AnnounceSchedule,
+    interfaces: Vec<String>,
+    secret: Option<Vec<u8>>,
+    password: Option<String>,
+    announced: bool,
+    announcement: Announcement,
+    server_task: Option<BackgroundTask>,
+    discovery_task: Arc<Mutex<Option<BackgroundTask>>>,
     clients: HostClientMap,
 }
 
 impl Host {
-    pub fn new(name: String) -> Host {
+    pub fn new(config: &Config) -> Host {
+        let id = match load_or_create_host_id(&config.id_file) {
+            Ok(id) => id,
+            Err(e) => {
+                eprintln!(
+                    "Failed to store host id in {}, using a temporary one, Error: {e}",
+                    config.id_file.display()
+                );
+                generate_host_id()
+            }
+        };
+        let mut message = DiscoveryMessage::new(
+            id.clone(),
+            config.name.clone(),
+            config.room.clone(),
+            config.group.clone(),
+            Vec::new(),
+        );
+        message.capacity = config.capacity;
+        message.password = config.password.is_some();
+        message.features = FEATURES;
+        message.topic = config.topic.clone();
         Host {
-            name,
+            id,
+            name: config.name.clone(),
+            discovery: config.discovery,
+            schedule: AnnounceSchedule::new(
+                config.announce_burst,
+                config.announce_interval,
+                config.announce_jitter,
+            ),
+            interfaces: config.interfaces.clone(),
+            secret: config.secret.clone(),
+            password: config.password.clone(),
+            announced: false,
+            announcement: Arc::new(watch::channel(message).0),
+            server_task: None,
+            discovery_task: Arc::new(Mutex::new(None)),
             clients: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 
-    pub async fn start_chat(&self, host: &String, client_port: &u16, host_port: &u16) {
-        let addr = format!("{}:{}", host, host_port);
-        let listener = TcpListener::bind(addr).await.unwrap();
+    // Rename the host, a running announcer sends the new name right away
+    fn rename(&mut self, name: String) {
+        self.announcement.send_modify(|m| m.name = name.clone());
+        self.name = name;
+        println!("> Host name is now {}", self.name);
+    }
+
+    fn set_topic(&mut self, topic: String) {
+        println!("> Topic is now '{}'", topic);
+        self.announcement.send_modify(|m| m.topic = topic);
+    }
 
-        // Setup shutdown signal
-        let (shutdown_tx, mut shutdown_rx) = watch::channel(false);
+    // Serve chat in the background and announce it, announcing stops with the server
+    pub async fn start(&mut self, host: String, client_port: u16, host_port: u16) {
+        if self.server_task.as_ref().is_some_and(|t| t.is_running()) {
+            println!("Chat server is already running");
+            return;
+        }
 
-        // Spawn task to read stdin and look for 'q'
-        let quit_task = quit_task_handler(shutdown_tx).await;
+        let addr = match parse_scoped_ip(&host) {
+            Some((ip, scope)) => scoped_socket_addr(ip, host_port, scope),
+            None => {
+                println!("Invalid host address {}", host);
+                return;
+            }
+        };
+        let listener = match TcpListener::bind(addr).await {
+            Ok(l) => l,
+            Err(e) => {
+                println!("Failed to start chat server on {addr}, Error: {e}");
+                return;
+            }
+        };
+        println!("> Chat server listening on {}", addr);
 
-        // TCP start chat server
-        let tcp_chat_server_task = {
-            let client_port = client_port.clone();
-            let client_map = self.clients.clone();
+        // TCP chat server
+        let client_map = self.clients.clone();
+        let announcement = Arc::clone(&self.announcement);
+        let discovery_task = Arc::clone(&self.discovery_task);
+        let password = self.password.clone();
+        let opened = Instant::now();
+        // Set under the room lock when the server stops, clients still saying hello are refused
+        let closed = Arc::new(AtomicBool::new(false));
+        self.server_task = Some(BackgroundTask::start(|mut shutdown_rx| {
             task::spawn(async move {
-                println!("Starting TCP server...");
                 loop {
                     select! {
-                        _ = shutdown_rx.changed() => {
-                            if *shutdown_rx.borrow() {
+                        res = shutdown_rx.changed() => {
+                            if res.is_err() || *shutdown_rx.borrow() {
                                 break;
                             }
                         }
-                        Ok((socket,addr)) = listener.accept() => {
-                            // if addr.port() != client_port {
-                            //     continue;
-                            // }
-                            println!("Client connected: {}", addr);
-                            task::spawn(handle_client(socket, addr, client_map.clone()));
+                        accepted = listener.accept() => {
+                            match accepted {
+                                Ok((socket, addr)) => {
+                                    task::spawn(handle_client(socket, addr, client_map.clone(), announcement.clone(), password.clone(), opened, closed.clone()));
+                                }
+                                Err(e) => {
+                                    eprintln!("Accept error: {}", e);
+                                    break;
+                                }
+                            }
                         }
                     }
                 }
+
+                // Nobody can join anymore, drop the clients and stop announcing
+                {
+                    let mut clients = client_map.lock().await;
+                    closed.store(true, Ordering::Relaxed);
+                    clients.clear();
+                }
+                announcement.send_modify(|m| m.occupancy = 0);
+                if let Some(t) = discovery_task.lock().await.take() {
+                    t.stop().await;
+                    println!("> Discovery stopped");
+                }
             })
-        };
+        }));
+
+        self.start_discovery(host, client_port, host_port).await;
+    }
 
-        // Wait for either task to finish
-        tokio::select! {
-            _ = quit_task => {}
-            _ = tcp_chat_server_task => {}
+    pub async fn stop(&mut self) {
+        if let Some(t) = self.server_task.take() {
+            t.stop().await;
+            println!("> Chat server stopped");
         }
     }
 
-    pub async fn broadcast_discovery_message(
-        &mut self,
-        host: String,
-        client_port: u16,
-        host_port: u16,
-    ) {
-        let socket = UdpSocket::bind(format!("{}:{}", host, host_port))
-            .await
-            .unwrap();
+    // Announce this host in the background, the REPL stays usable meanwhile
+    pub async fn start_discovery(&mut self, host: String, client_port: u16, host_port: u16) {
+        if !self.server_task.as_ref().is_some_and(|t| t.is_running()) {
+            println!("Chat server is not running, START it first");
+            return;
+        }
+        let mut discovery_task = self.discovery_task.lock().await;
+        if discovery_task.as_ref().is_some_and(|t| t.is_running()) {
+            println!("Discovery is already running");
+            return;
+        }
+
+        // TCP address the chat server listens on, announced to clients
+        let tcp_addr = match parse_scoped_ip(&host) {
+            Some((ip, scope)) => scoped_socket_addr(ip, host_port, scope),
+            None => {
+                println!("Invalid host address {}", host);
+                return;
+            }
+        };
 
-        // Enable broadcast mode
-        socket.set_broadcast(true).unwrap();
+        // Broadcast or multicast group address
+        let target_addr = self.discovery.target(client_port);
 
-        // Setup shutdown signal
-        let (shutdown_tx, mut shutdown_rx) = watch::channel(false);
+        // Announce from ephemeral ports, clients read the tcp port from the packet
+        let announcer =
+            match Announcer::bind(&host, self.discovery, client_port, &self.interfaces).await {
+                Ok(a) => a,
+                Err(e) => {
+                    println!("Failed to start announcing, Error: {e}");
+                    return;
+                }
+            };
+        announcer.print_routes();
 
-        // Bradcasting address
-        let target_addr: SocketAddr = format!("255.255.255.255:{}", client_port).parse().unwrap();
+        // Clients probe the host port, answer them with a unicast announce
+        let probe_socket = match bind_shared_udp(bind_addr(&host, host_port, &target_addr)) {
+            Ok(s) => s,
+            Err(e) => {
+                println!("Failed to listen for probes on port {host_port}, Error: {e}");
+                return;
+            }
+        };
+        if let Err(e) = join_discovery_group(&probe_socket, self.discovery, &host) {
+            println!(
+                "Failed to join discovery group {}, Error: {e}",
+                target_addr.ip()
+            );
+            return;
+        }
+        // Pings come to a port of our own, unicast to the shared port reaches only one of
+        // the processes listening on it
+        let ping_socket = match UdpSocket::bind(bind_addr(&host, 0, &target_addr)).await {
+            Ok(s) => s,
+            Err(e) => {
+                println!("Failed to listen for pings, Error: {e}");
+                return;
+            }
+        };
+        let ping_port = ping_socket.local_addr().map(|a| a.port()).unwrap_or(0);
+        let mut buf = [0; 1024];
+        let mut ping_buf = [0; 1024];
 
-        // Spawn task to read stdin and look for 'q'
-        let quit_task = quit_task_handler(shutdown_tx).await;
+        // Discovery packet, same for every send apart from the auth trailer
+        self.announcement.send_modify(|m| {
+            m.endpoints = vec![tcp_addr];
+            m.ping_port = ping_port;
+        });
+        let mut state_rx = self.announcement.subscribe();
+        let mut packet = state_rx.borrow_and_update().encode();
+        self.announced = true;
 
         // UDP host discovery messages
-        let udp_task = task::spawn(async move {
-            let mut ticker = interval(Duration::from_secs(1));
-            loop {
-                select! {
-                    _ = shutdown_rx.changed() => {
-                        if *shutdown_rx.borrow() {
-                            break;
+        let mut schedule = self.schedule.clone();
+        let mut auth = PacketAuth::new(self.secret.clone());
+        *discovery_task = Some(BackgroundTask::start(|mut shutdown_rx| {
+            task::spawn(async move {
+                let mut announcer = announcer;
+                let next_announce = sleep(Duration::ZERO);
+                pin!(next_announce);
+                let mut address_ticker = interval(ADDRESS_CHECK_INTERVAL);
+                loop {
+                    select! {
+                        res = shutdown_rx.changed() => {
+                            if res.is_err() || *shutdown_rx.borrow() {
+                                break;
+                            }
                         }
-                    }
-                    _ = ticker.tick() => {
-                        let msg: String = format!("Hello from cli broadcaster!");
-                        match socket.send_to(msg.as_bytes(), &target_addr).await {
-                            Ok(_) => {},
-                            Err(e) => {
+                        _ = &mut next_announce => {
+                            if let Err(e) = announcer.send(&auth, &packet).await {
                                 eprintln!("Failed to send: {}", e);
                                 break;
-                            },
+                            }
+                            next_announce.as_mut().reset(Instant::now() + schedule.next_delay());
+                        }
+                        res = state_rx.changed() => {
+                            if res.is_err() {
+                                break;
+                            }
+                            // Name or occupancy changed, announce now and burst again
+                            packet = state_rx.borrow_and_update().encode();
+                            schedule.reset();
+                            next_announce.as_mut().reset(Instant::now());
+                        }
+                        _ = address_ticker.tick() => {
+                            if announcer.addresses_changed() {
+                                match announcer.rebind().await {
+                                    Ok(_) => {
+                                        println!("> Interface addresses changed");
+                                        announcer.print_routes();
+                                        schedule.reset();
+                                        next_announce.as_mut().reset(Instant::now());
+                                    }
+                                    Err(e) => eprintln!("Failed to rebind announcer: {}", e),
+                                }
+                            }
+                        }
+                        result = probe_socket.recv_from(&mut buf) => {
+                            match result {
+                                Ok((n, addr)) => {
+                                    let body = match auth.open(&buf[..n]) {
+                                        Ok(body) => body,
+                                        Err(e) => {
+                                            eprintln!("> rejected probe from {}: {}", addr, e);
+                                            continue;
+                                        }
+                                    };
+                                    if !matches!(DiscoveryPacket::decode(body, &addr), Ok(DiscoveryPacket::Probe)) {
+                                        continue;
+                                    }
+                                    if let Err(e) = probe_socket.send_to(&auth.seal(packet.clone()), &addr).await {
+                                        eprintln!("Failed to answer {}: {}", addr, e);
+                                    }
+                                }
+                                Err(e) => {
+                                    eprintln!("UDP recv error: {}", e);
+                                    break;
+                                }
+                            }
+                        }
+                        result = ping_socket.recv_from(&mut ping_buf) => {
+                            match result {
+                                Ok((n, addr)) => {
+                                    let Ok(body) = auth.open(&ping_buf[..n]) else {
+                                        continue;
+                                    };
+                                    let Ok(DiscoveryPacket::Ping { nonce }) = DiscoveryPacket::decode(body, &addr) else {
+                                        continue;
+                                    };
+                                    let pong = auth.seal(DiscoveryPacket::encode_pong(nonce));
+                                    if let Err(e) = ping_socket.send_to(&pong, &addr).await {
+                                        eprintln!("Failed to answer {}: {}", addr, e);
+                                    }
+                                }
+                                Err(e) => {
+                                    eprintln!("UDP recv error: {}", e);
+                                    break;
+                                }
+                            }
                         }
                     }
                 }
+
+                // Tell clients this host is gone
+                let goodbye = state_rx.borrow().encode_goodbye();
+                if let Err(e) = announcer.send(&auth, &goodbye).await {
+                    eprintln!("Failed to send goodbye: {}", e);
+                }
+            })
+        }));
+
+        println!("> Announcing in the background, DISCOVERY STOP to stop");
+    }
+
+    pub async fn stop_discovery(&mut self) {
+        let task = self.discovery_task.lock().await.take();
+        if let Some(t) = task {
+            t.stop().await;
+            println!("> Discovery stopped");
+        }
+    }
+
+    // Send `text` to everyone in the room under the host's name
+    async fn say(&self, text: &str) {
+        let message = ChatMessage::Chat {
+            from: self.name.clone(),
+            text: text.to_string(),
+        };
+        broadcast(&self.clients, None, message).await;
+    }
+
+    // Chat in the own room until q or /quit, the room's traffic keeps printing meanwhile
+    async fn chat(&self) {
+        if !self.server_task.as_ref().is_some_and(|t| t.is_running()) {
+            println!("Chat server is not running => START");
+            return;
+        }
+        println!(
+            "> Chatting as {}, type to chat, q or /quit to return to the prompt",
+            self.name
+        );
+        let mut stdin = BufReader::new(io::stdin()).lines();
+        loop {
+            let text = match stdin.next_line().await {
+                Ok(Some(text)) => text,
+                // stdin closed
+                Ok(None) => break,
+                Err(e) => {
+                    eprintln!("Failed to read stdin: {:?}", e);
+                    break;
+                }
+            };
+            match text.trim() {
+                "q" | "/quit" => break,
+                "/who" => self.print_members().await,
+                "" => {}
+                text => self.say(text).await,
             }
-        });
+        }
+    }
 
-        println!("> Enter q then ENTER for exit discovering");
-        println!("> Sending...");
+    // WHO, everyone in the room and how long they have been in it
+    async fn print_members(&self) {
+        let Some(t) = self.server_task.as_ref().filter(|t| t.is_running()) else {
+            println!("Chat server is not running => START");
+            return;
+        };
+        let members = member_list(&self.name, t.started.into(), &*self.clients.lock().await);
+        println!("> {}", ChatMessage::Members { members });
+    }
 
-        // Wait for either task to finish
-        tokio::select! {
-            _ = quit_task => {}
-            _ = udp_task => {}
+    // Throw the client named `name` out of the room
+    async fn kick(&self, name: &str) {
+        let clients = self.clients.lock().await;
+        match clients.values().find(|m| m.name.eq_ignore_ascii_case(name)) {
+            Some(member) => member.kick.notify_one(),
+            None => println!("Nobody named {} is in the room", name),
         }
+    }
 
-        // Ok(())
+    async fn print_discovery_status(&self) {
+        match &self.server_task {
+            Some(t) if t.is_running() => println!(
+                "Chat server running for {}s with {} client(s)",
+                t.started.elapsed().as_secs(),
+                self.clients.lock().await.len()
+            ),
+            _ => println!("Chat server stopped"),
+        }
+        match &*self.discovery_task.lock().await {
+            Some(t) if t.is_running() => println!(
+                "Announcing for {}s ({:?}) every {}s as {} [{}]",
+                t.started.elapsed().as_secs(),
+                self.discovery,
+                self.schedule.steady().as_secs(),
+                self.name,
+                self.id
+            ),
+            _ => println!("Discovery stopped"),
+        }
+    }
+
+    // Called on EXIT, stops serving or repeats the goodbye in case the last one got lost
+    pub async fn leave(&mut self, host: &str, client_port: u16) {
+        if self.server_task.is_some() {
+            self.stop().await;
+            return;
+        }
+        if self.discovery_task.lock().await.is_some() {
+            self.stop_discovery().await;
+            return;
+        }
+        if !self.announced {
+            return;
+        }
+        let announcer =
+            match Announcer::bind(host, self.discovery, client_port, &self.interfaces).await {
+                Ok(a) => a,
+                Err(e) => {
+                    eprintln!("Failed to send goodbye: {}", e);
+                    return;
+                }
+            };
+        let goodbye = self.announcement.borrow().encode_goodbye();
+        let auth = PacketAuth::new(self.secret.clone());
+        if let Err(e) = announcer.send(&auth, &goodbye).await {
+            eprintln!("Failed to send goodbye: {}", e);
+        }
+        self.announced = false;
     }
 }
 
 impl fmt::Display for Host {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", "Host")
+        write!(f, "Host")
     }
 }
 
@@ -144,20 +521,39 @@ impl UserTrait for Host {
     async fn execute_command(
         &mut self,
         cmd: &super::command::Command,
-        host: &String,
+        host: &str,
         client_port: &u16,
         host_port: &u16,
     ) -> Result<(), String> {
         match &cmd.command_type {
-            Some(ty) => match ty {
-                CommandType::Start => {
-                    self.start_chat(host, client_port, host_port).await;
+            Some(CommandType::Start) => {
+                self.start(host.to_string(), *client_port, *host_port).await;
+            }
+            Some(CommandType::Stop) => self.stop().await,
+            Some(CommandType::Name) => {
+                if cmd.args.is_empty() {
+                    println!("New name required => NAME <name>")
+                } else {
+                    self.rename(cmd.args.join(" "));
                 }
-                _ => {
-                    println!("Invalid command!")
+            }
+            Some(CommandType::Topic) => self.set_topic(cmd.args.join(" ")),
+            Some(CommandType::Chat) => self.chat().await,
+            Some(CommandType::Who) => self.print_members().await,
+            Some(CommandType::Kick) => {
+                if cmd.args.is_empty() {
+                    println!("Name required => KICK <name>")
+                } else {
+                    self.kick(&cmd.args.join(" ")).await;
                 }
-            },
-            None => {
+            }
+            Some(CommandType::DiscoveryStart) => {
+                self.start_discovery(host.to_string(), *client_port, *host_port)
+                    .await;
+            }
+            Some(CommandType::DiscoveryStop) => self.stop_discovery().await,
+            Some(CommandType::DiscoveryStatus) => self.print_discovery_status().await,
+            _ => {
                 println!("Invalid command!")
             }
         }
@@ -165,53 +561,140 @@ impl UserTrait for Host {
     }
 }
 
-async fn handle_client(mut socket: TcpStream, addr: SocketAddr, client_map: HostClientMap) {
-    let sender;
-    let mut receiver;
-    {
+async fn handle_client(
+    mut socket: TcpStream,
+    addr: SocketAddr,
+    client_map: HostClientMap,
+    announcement: Announcement,
+    password: Option<String>,
+    opened: Instant,
+    closed: Arc<AtomicBool>,
+) {
+    let (reader, mut writer) = socket.split();
+    let mut frames = FrameReader::new(reader);
+
+    // Nothing else is accepted before the client said who it is
+    let hello = match timeout(HELLO_TIMEOUT, frames.next()).await {
+        Ok(Ok(Some(ChatMessage::Hello {
+            name,
+            password: given,
+        }))) => Ok((name.trim().to_string(), given)),
+        Ok(Ok(Some(_))) => Err(String::from("Expected hello")),
+        Ok(Ok(None)) => return,
+        Ok(Err(e)) => {
+            eprintln!("Read error from {}: {:?}", addr, e);
+            return;
+        }
+        Err(_) => {
+            println!("Client {} sent no hello in time", addr);
+            return;
+        }
+    };
+
+    let (sender, mut receiver) = mpsc::channel::<ChatMessage>(10);
+    let kick = Arc::new(Notify::new());
+    // Checked and inserted under one lock so two clients cannot take the same name
+    let admitted = {
         let mut clients = client_map.lock().await;
-        let (tx, rx) = mpsc::channel::<Message>(10);
-        clients.insert(addr, tx.clone());
-        sender = tx;
-        receiver = rx;
+        let admitted = hello.and_then(|(name, given)| {
+            if closed.load(Ordering::Relaxed) {
+                return Err(String::from("Room is closed"));
+            }
+            check_hello(
+                &name,
+                &given,
+                password.as_deref(),
+                &announcement.borrow(),
+                &clients,
+            )
+            .map(|_| name)
+        });
+        if let Ok(name) = &admitted {
+            clients.insert(
+                addr,
+                Member {
+                    name: name.clone(),
+                    joined: Instant::now(),
+                    tx: sender,
+                    kick: Arc::clone(&kick),
+                },
+            );
+            let occupancy = clients.len() as u16;
+            announcement.send_modify(|m| m.occupancy = occupancy);
+        }
+        admitted
+    };
+    let name = match admitted {
+        Ok(name) => name,
+        Err(text) => {
+            println!("Refused client {}: {}", addr, text);
+            let _ = write_message(&mut writer, &ChatMessage::Error { text }).await;
+            return;
+        }
+    };
+    let joined = ChatMessage::Join { name: name.clone() };
+    println!("> {}", joined);
+    broadcast(&client_map, Some(addr), joined).await;
+    let welcome = ChatMessage::System {
+        text: format!(
+            "Welcome to {}, you are {}",
+            announcement.borrow().room,
+            name
+        ),
+    };
+    if let Err(e) = write_message(&mut writer, &welcome).await {
+        eprintln!("Write error: {:?}", e);
     }
 
-    let (reader, mut writer) = socket.split();
-    let mut buf_reader = BufReader::new(reader);
-    let mut line = String::new();
+    // Keeps idle connections alive through NAT and firewalls
+    let mut keepalive = interval_at(Instant::now() + KEEPALIVE_INTERVAL, KEEPALIVE_INTERVAL);
+    let mut last_heard = Instant::now();
 
-    loop {
+    let reason = loop {
         select! {
             // Read from socket
-            read = buf_reader.read_line(&mut line) => {
+            read = frames.next() => {
+                if let Ok(Some(_)) = read {
+                    last_heard = Instant::now();
+                }
                 match read {
-                    Ok(0) => {
-                        let mut clients = client_map.lock().await;
-                        clients.remove(&addr);
-                        println!("Client disconnected");
-                        break;
+                    Ok(None) => break LeaveReason::Quit,
+                    Ok(Some(ChatMessage::Who)) => {
+                        let members = {
+                            let host_name = announcement.borrow().name.clone();
+                            member_list(&host_name, opened, &*client_map.lock().await)
+                        };
+                        if let Err(e) = write_message(&mut writer, &ChatMessage::Members { members }).await {
+                            eprintln!("Write error: {:?}", e);
+                            break LeaveReason::Timeout;
+                        }
                     }
-                    Ok(_) => {
-                        println!("Received from client: {}", line.trim_end());
-                        let clients = client_map.lock().await;
-
-                        for (c_addr, tx) in clients.iter() {
-                            if *c_addr != addr {
-                                // skip sender if you want
-                                let m = Message {
-                                    producer: format!("{}:{}",addr.ip(), addr.port()),
-                                    message: line.clone()
-                                };
-                                if let Err(e) = tx.send(m).await {
-                                    eprintln!("Failed to send to {}: {:?}", addr, e);
-                                }
-                            }
+                    Ok(Some(ChatMessage::Chat { text, .. })) => {
+                        // The sender is whoever owns the connection, not what it claims
+                        let message = ChatMessage::Chat { from: name.clone(), text };
+                        println!("> {}", message);
+                        broadcast(&client_map, Some(addr), message).await;
+                    }
+                    Ok(Some(ChatMessage::Ping { nonce })) => {
+                        if let Err(e) = write_message(&mut writer, &ChatMessage::Pong { nonce }).await {
+                            eprintln!("Write error: {:?}", e);
+                            break LeaveReason::Timeout;
                         }
-                        line.clear(); // important to reuse buffer!
                     }
+                    Ok(Some(ChatMessage::Pong { .. })) => {}
+                    Ok(Some(other)) => {
+                        let refused = ChatMessage::Error {
+                            text: format!("Unexpected message: {}", other),
+                        };
+                        if let Err(e) = write_message(&mut writer, &refused).await {
+                            eprintln!("Write error: {:?}", e);
+                            break LeaveReason::Timeout;
+                        }
+                    }
+                    // A broken connection counts as timed out
                     Err(e) => {
-                        eprintln!("Read error: {:?}", e);
-                        break;
+                        eprintln!("Read error from {}: {:?}", addr, e);
+                        break LeaveReason::Timeout;
                     }
                 }
             }
@@ -219,19 +702,262 @@ async fn handle_client(mut socket: TcpStream, addr: SocketAddr, client_map: Host
             // Receive a message from another task
             msg = receiver.recv() => {
                 match msg {
-                    Some(text) => {
-                        if let Err(e) = writer.write_all(text.message.as_bytes()).await {
+                    Some(message) => {
+                        if let Err(e) = write_message(&mut writer, &message).await {
                             eprintln!("Write error: {:?}", e);
-                            break;
+                            break LeaveReason::Timeout;
                         }
                     }
                     None => {
-                        // All senders dropped
-                        println!("Message channel closed");
-                        break;
+                        // All senders dropped, the server stopped
+                        break LeaveReason::Quit;
                     }
                 }
             }
+
+            _ = keepalive.tick() => {
+                if last_heard.elapsed() > CLIENT_TIMEOUT {
+                    let gone = ChatMessage::Error { text: String::from("Timed out") };
+                    let _ = write_message(&mut writer, &gone).await;
+                    break LeaveReason::Timeout;
+                }
+                let ping = ChatMessage::Ping { nonce: random_u64() };
+                if let Err(e) = write_message(&mut writer, &ping).await {
+                    eprintln!("Write error: {:?}", e);
+                    break LeaveReason::Timeout;
+                }
+            }
+
+            _ = kick.notified() => {
+                let kicked = ChatMessage::Error { text: String::from("You were kicked") };
+                let _ = write_message(&mut writer, &kicked).await;
+                break LeaveReason::Kicked;
+            }
         }
+    };
+
+    // Gone for whatever reason, it no longer counts towards the occupancy
+    {
+        let mut clients = client_map.lock().await;
+        if clients.remove(&addr).is_none() {
+            // The server stopped and closed the room already
+            return;
+        }
+        let occupancy = clients.len() as u16;
+        announcement.send_modify(|m| m.occupancy = occupancy);
+    }
+    let left = ChatMessage::Leave { name, reason };
+    println!("> {}", left);
+    broadcast(&client_map, Some(addr), left).await;
+}
+
+// Ok when the client may join, the reason it may not otherwise
+fn check_hello(
+    name: &str,
+    given: &str,
+    password: Option<&str>,
+    host: &DiscoveryMessage,
+    clients: &HashMap<SocketAddr, Member>,
+) -> Result<(), String> {
+    // Counted here, under the lock the client is added with, so joining at once cannot overfill
+    if host.capacity != 0 && clients.len() >= host.capacity as usize {
+        return Err(String::from("Room is full"));
+    }
+    if password.is_some_and(|p| p != given) {
+        return Err(String::from("Wrong password"));
+    }
+    if name.is_empty() || name.chars().count() > MAX_NAME_LEN {
+        return Err(format!("Name must be 1 to {} characters", MAX_NAME_LEN));
+    }
+    if name.chars().any(char::is_control) {
+        return Err(String::from("Name must not contain control characters"));
+    }
+    // The host chats in the room under its own name
+    if host.name.eq_ignore_ascii_case(name)
+        || clients.values().any(|m| m.name.eq_ignore_ascii_case(name))
+    {
+        return Err(format!("Name {} is already taken", name));
+    }
+    Ok(())
+}
+
+// Everyone in the room, the host first and then clients by how long they have been in
+fn member_list(
+    host_name: &str,
+    opened: Instant,
+    clients: &HashMap<SocketAddr, Member>,
+) -> Vec<MemberInfo> {
+    let mut members: Vec<&Member> = clients.values().collect();
+    members.sort_by_key(|m| m.joined);
+    let host = MemberInfo {
+        name: host_name.to_string(),
+        connected_secs: opened.elapsed().as_secs(),
+    };
+    std::iter::once(host)
+        .chain(members.into_iter().map(|m| MemberInfo {
+            name: m.name.clone(),
+            connected_secs: m.joined.elapsed().as_secs(),
+        }))
+        .collect()
+}
+
+// Hand `message` to every client but `except`. The lock is released before sending, and
+// a client whose queue is full misses the message, so a stuck client cannot stall the room
+async fn broadcast(client_map: &HostClientMap, except: Option<SocketAddr>, message: ChatMessage) {
+    let recipients: Vec<(String, mpsc::Sender<ChatMessage>)> = client_map
+        .lock()
+        .await
+        .iter()
+        .filter(|(c_addr, _)| Some(**c_addr) != except)
+        .map(|(_, member)| (member.name.clone(), member.tx.clone()))
+        .collect();
+    for (name, tx) in recipients {
+        match tx.try_send(message.clone()) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                eprintln!("{} is not keeping up, dropped a message for it", name)
+            }
+            // Left meanwhile, its leave message follows
+            Err(TrySendError::Closed(_)) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn member(name: &str, capacity: usize) -> (Member, mpsc::Receiver<ChatMessage>) {
+        let (tx, rx) = mpsc::channel(capacity);
+        let member = Member {
+            name: name.to_string(),
+            joined: Instant::now(),
+            tx,
+            kick: Arc::new(Notify::new()),
+        };
+        (member, rx)
+    }
+
+    fn chat(text: &str) -> ChatMessage {
+        ChatMessage::Chat {
+            from: String::from("alice"),
+            text: text.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn broadcast_is_not_stalled_by_a_member_that_does_not_drain() {
+        let client_map: HostClientMap = Arc::new(Mutex::new(HashMap::new()));
+        let (slow, _slow_rx) = member("slow", 1);
+        let (fast, mut fast_rx) = member("fast", 100);
+        let sender: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        client_map
+            .lock()
+            .await
+            .insert("127.0.0.1:2".parse().unwrap(), slow);
+        client_map
+            .lock()
+            .await
+            .insert("127.0.0.1:3".parse().unwrap(), fast);
+
+        // The slow member never reads, its queue fills after the first message
+        let sent = timeout(Duration::from_secs(1), async {
+            for i in 0..20 {
+                broadcast(&client_map, Some(sender), chat(&i.to_string())).await;
+            }
+        })
+        .await;
+        assert!(sent.is_ok(), "broadcast stalled on a full queue");
+
+        for i in 0..20 {
+            assert_eq!(fast_rx.recv().await, Some(chat(&i.to_string())));
+        }
+        assert!(client_map.try_lock().is_ok(), "broadcast kept the lock");
+    }
+
+    #[tokio::test]
+    async fn members_can_take_the_lock_while_a_broadcast_runs() {
+        let client_map: HostClientMap = Arc::new(Mutex::new(HashMap::new()));
+        let (slow, _slow_rx) = member("slow", 1);
+        client_map
+            .lock()
+            .await
+            .insert("127.0.0.1:2".parse().unwrap(), slow);
+
+        // One task keeps broadcasting into the full queue while another needs the lock,
+        // as a member answering WHO does
+        let room = Arc::clone(&client_map);
+        let talker = task::spawn(async move {
+            for i in 0..100 {
+                broadcast(&room, None, chat(&i.to_string())).await;
+                task::yield_now().await;
+            }
+        });
+        let who = timeout(Duration::from_secs(1), async {
+            for _ in 0..100 {
+                let clients = client_map.lock().await;
+                assert_eq!(member_list("host", Instant::now(), &clients).len(), 2);
+                drop(clients);
+                task::yield_now().await;
+            }
+        })
+        .await;
+        assert!(who.is_ok(), "the room deadlocked");
+        assert!(timeout(Duration::from_secs(1), talker).await.is_ok());
+    }
+
+    fn room(capacity: u16) -> DiscoveryMessage {
+        let mut host = DiscoveryMessage::test("a", "default", "192.0.2.1:4600");
+        host.capacity = capacity;
+        host
+    }
+
+    #[test]
+    fn hello_is_refused_once_the_room_is_full() {
+        let mut clients = HashMap::new();
+        let host = room(2);
+        for (i, name) in ["alice", "bob"].into_iter().enumerate() {
+            assert_eq!(check_hello(name, "", None, &host, &clients), Ok(()));
+            let addr = SocketAddr::from(([127, 0, 0, 1], 10 + i as u16));
+            clients.insert(addr, member(name, 1).0);
+        }
+        assert_eq!(
+            check_hello("carol", "", None, &host, &clients),
+            Err(String::from("Room is full"))
+        );
+        assert_eq!(check_hello("carol", "", None, &room(0), &clients), Ok(()));
+    }
+
+    #[test]
+    fn hello_checks_password_and_name() {
+        let mut clients = HashMap::new();
+        let host = room(0);
+        clients.insert(SocketAddr::from(([127, 0, 0, 1], 10)), member("alice", 1).0);
+        assert!(check_hello("bob", "nope", Some("pw"), &host, &clients).is_err());
+        assert!(check_hello("ALICE", "pw", Some("pw"), &host, &clients).is_err());
+        assert!(check_hello("HOST-A", "pw", Some("pw"), &host, &clients).is_err());
+        assert!(check_hello("", "pw", Some("pw"), &host, &clients).is_err());
+        assert!(check_hello("b\nob", "pw", Some("pw"), &host, &clients).is_err());
+        assert_eq!(
+            check_hello("bob", "pw", Some("pw"), &host, &clients),
+            Ok(())
+        );
+    }
+
+    #[tokio::test]
+    async fn broadcast_skips_the_sender() {
+        let client_map: HostClientMap = Arc::new(Mutex::new(HashMap::new()));
+        let (alice, mut alice_rx) = member("alice", 10);
+        let (bob, mut bob_rx) = member("bob", 10);
+        let alice_addr: SocketAddr = "127.0.0.1:2".parse().unwrap();
+        client_map.lock().await.insert(alice_addr, alice);
+        client_map
+            .lock()
+            .await
+            .insert("127.0.0.1:3".parse().unwrap(), bob);
+
+        broadcast(&client_map, Some(alice_addr), chat("hi")).await;
+        assert_eq!(bob_rx.try_recv().ok(), Some(chat("hi")));
+        assert!(alice_rx.try_recv().is_err());
     }
 }
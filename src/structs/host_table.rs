@@ -0,0 +1,587 @@
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    fs, io,
+    net::SocketAddr,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::global::helper::{format_age, random_u64};
+
+use super::{
+    discovery::{DiscoveryMessage, DiscoveryPacket, feature_names},
+    rtt::RttStats,
+};
+
+// Cached hosts not seen for this long are not loaded again
+const CACHE_MAX_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+const TABLE_HEADER: [&str; 15] = [
+    "#", "NAME", "ROOM", "GROUP", "TOPIC", "USERS", "PASSWORD", "FEATURES", "ADDRESS", "ID",
+    "HOPS", "RTT", "LOSS", "JITTER", "SEEN",
+];
+
+// Row order of `print_table`
+pub enum HostOrder {
+    Name,
+    // Fastest first, hosts without a measurement last
+    Latency,
+}
+
+pub struct HostEntry {
+    pub message: DiscoveryMessage,
+    pub last_seen: SystemTime,
+    // Loaded from the cache and not confirmed by a live announcement yet
+    pub stale: bool,
+    pub rtt: RttStats,
+}
+
+pub enum HostEvent {
+    Appeared(DiscoveryMessage),
+    WentAway(DiscoveryMessage),
+    Left(DiscoveryMessage),
+    Moved {
+        from: SocketAddr,
+        to: DiscoveryMessage,
+    },
+}
+
+impl HostEvent {
+    pub fn print(&self) {
+        match self {
+            HostEvent::Appeared(dm) => println!(
+                "> host appeared: {} [{}] group '{}' room '{}' at {}",
+                dm.name,
+                dm.host_id,
+                dm.group,
+                dm.room,
+                endpoint_text(dm)
+            ),
+            HostEvent::WentAway(dm) => println!(
+                "> host went away: {} [{}] at {}",
+                dm.name,
+                dm.host_id,
+                endpoint_text(dm)
+            ),
+            HostEvent::Left(dm) => println!(
+                "> host left: {} [{}] at {}",
+                dm.name,
+                dm.host_id,
+                endpoint_text(dm)
+            ),
+            HostEvent::Moved { from, to } => println!(
+                "> host moved: {} [{}] from {} to {}",
+                to.name,
+                to.host_id,
+                from,
+                endpoint_text(to)
+            ),
+        }
+    }
+}
+
+// Hosts known to a client, keyed by host id so address changes update the same entry
+pub struct HostTable {
+    ttl: Duration,
+    // Only hosts of these groups are recorded, empty means every group
+    groups: Vec<String>,
+    entries: HashMap<String, HostEntry>,
+}
+
+impl HostTable {
+    pub fn new(ttl: Duration, groups: Vec<String>) -> HostTable {
+        HostTable {
+            ttl,
+            groups,
+            entries: HashMap::new(),
+        }
+    }
+
+    pub fn groups(&self) -> &[String] {
+        &self.groups
+    }
+
+    // Change the group filter, hosts outside the new groups are forgotten
+    pub fn set_groups(&mut self, groups: Vec<String>) {
+        self.groups = groups;
+        let groups = &self.groups;
+        self.entries
+            .retain(|_, entry| groups.is_empty() || groups.contains(&entry.message.group));
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &DiscoveryMessage> {
+        self.entries.values().map(|e| &e.message)
+    }
+
+    // Record an announcement, returns an event if the host is new or moved.
+    // Hosts of other groups are ignored
+    pub fn upsert(&mut self, dm: DiscoveryMessage) -> Option<HostEvent> {
+        if !self.groups.is_empty() && !self.groups.contains(&dm.group) {
+            return None;
+        }
+        let now = SystemTime::now();
+        match self.entries.get_mut(&dm.host_id) {
+            // Cached host is alive again
+            Some(entry) if entry.stale => {
+                entry.message = dm;
+                entry.last_seen = now;
+                entry.stale = false;
+                Some(HostEvent::Appeared(entry.message.clone()))
+            }
+            Some(entry) => {
+                let from = entry.message.endpoint();
+                let moved = from != dm.endpoint();
+                entry.message = dm;
+                entry.last_seen = now;
+                match from {
+                    Some(from) if moved => Some(HostEvent::Moved {
+                        from,
+                        to: entry.message.clone(),
+                    }),
+                    _ => None,
+                }
+            }
+            None => {
+                let event = HostEvent::Appeared(dm.clone());
+                self.entries.insert(
+                    dm.host_id.clone(),
+                    HostEntry {
+                        message: dm,
+                        last_seen: now,
+                        stale: false,
+                        rtt: RttStats::default(),
+                    },
+                );
+                Some(event)
+            }
+        }
+    }
+
+    // Host said goodbye, forget it right away
+    pub fn remove_by_id(&mut self, host_id: &str) -> Option<HostEvent> {
+        self.entries
+            .remove(host_id)
+            .map(|entry| HostEvent::Left(entry.message))
+    }
+
+    // Start a ping to every live host `reachable` accepts, returns the nonce and address of each
+    pub fn start_pings(
+        &mut self,
+        reachable: impl Fn(&SocketAddr) -> bool,
+    ) -> Vec<(u64, SocketAddr)> {
+        self.entries
+            .values_mut()
+            .filter(|entry| !entry.stale)
+            .filter_map(|entry| {
+                let addr = entry.message.ping_addr().filter(|a| reachable(a))?;
+                let nonce = random_u64();
+                entry.rtt.sent(nonce);
+                Some((nonce, addr))
+            })
+            .collect()
+    }
+
+    pub fn record_pong(&mut self, nonce: u64) {
+        for entry in self.entries.values_mut() {
+            if entry.rtt.answered(nonce) {
+                return;
+            }
+        }
+    }
+
+    // Print the hosts as a table, columns are as wide as their longest value
+    pub fn print_table(&self, order: HostOrder) {
+        let mut entries: Vec<(&String, &HostEntry)> = self.entries.iter().collect();
+        match order {
+            HostOrder::Name => entries.sort_by(|a, b| a.1.message.name.cmp(&b.1.message.name)),
+            HostOrder::Latency => entries.sort_by_key(|(_, entry)| {
+                let latency = entry.rtt.latency();
+                (latency.is_none(), latency)
+            }),
+        }
+        let rows: Vec<[String; 15]> = entries
+            .into_iter()
+            .enumerate()
+            .map(|(i, (host_id, entry))| {
+                let dm = &entry.message;
+                [
+                    (i + 1).to_string(),
+                    dm.name.clone(),
+                    dm.room.clone(),
+                    dm.group.clone(),
+                    if dm.topic.is_empty() {
+                        String::from("-")
+                    } else {
+                        dm.topic.clone()
+                    },
+                    if dm.capacity == 0 {
+                        dm.occupancy.to_string()
+                    } else {
+                        format!("{}/{}", dm.occupancy, dm.capacity)
+                    },
+                    String::from(if dm.password { "yes" } else { "no" }),
+                    feature_names(dm.features),
+                    endpoint_text(dm),
+                    host_id.clone(),
+                    dm.path.hops.to_string(),
+                    entry.rtt.latency().map_or(String::from("-"), format_ms),
+                    entry
+                        .rtt
+                        .loss()
+                        .map_or(String::from("-"), |l| format!("{:.0}%", l * 100.0)),
+                    entry.rtt.jitter().map_or(String::from("-"), format_ms),
+                    format!(
+                        "{} ago{}",
+                        format_age(entry.last_seen.elapsed().unwrap_or_default()),
+                        if entry.stale { " (stale)" } else { "" }
+                    ),
+                ]
+            })
+            .collect();
+
+        let mut widths = TABLE_HEADER.map(str::len);
+        for row in &rows {
+            for (w, cell) in widths.iter_mut().zip(row) {
+                *w = (*w).max(cell.chars().count());
+            }
+        }
+        print_row(&TABLE_HEADER, &widths);
+        for row in &rows {
+            print_row(row, &widths);
+        }
+    }
+
+    // Drop every live host not heard from within the ttl, stale ones wait for confirmation
+    pub fn expire(&mut self) -> Vec<HostEvent> {
+        let mut events = Vec::new();
+        self.entries.retain(|_, entry| {
+            if !entry.stale && entry.last_seen.elapsed().unwrap_or_default() > self.ttl {
+                events.push(HostEvent::WentAway(entry.message.clone()));
+                false
+            } else {
+                true
+            }
+        });
+        events
+    }
+
+    // Add the hosts cached at `path` as stale entries, returns how many were added
+    pub fn load(&mut self, path: &Path) -> io::Result<usize> {
+        let content = match fs::read_to_string(path) {
+            Ok(c) => c,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e),
+        };
+        let mut added = 0;
+        for line in content.lines() {
+            let Some((last_seen, dm)) = parse_cache_line(line) else {
+                continue;
+            };
+            if last_seen.elapsed().unwrap_or_default() > CACHE_MAX_AGE
+                || (!self.groups.is_empty() && !self.groups.contains(&dm.group))
+                || self.entries.contains_key(&dm.host_id)
+            {
+                continue;
+            }
+            self.entries.insert(
+                dm.host_id.clone(),
+                HostEntry {
+                    message: dm,
+                    last_seen,
+                    stale: true,
+                    rtt: RttStats::default(),
+                },
+            );
+            added += 1;
+        }
+        Ok(added)
+    }
+
+    // Write every known host to `path`, one `last seen ms | source | hex announce` line each
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut content = String::new();
+        for entry in self.entries.values() {
+            let millis = entry
+                .last_seen
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis())
+                .unwrap_or_default();
+            let mut hex = String::new();
+            for b in entry.message.encode() {
+                let _ = write!(hex, "{:02x}", b);
+            }
+            let _ = writeln!(content, "{}\t{}\t{}", millis, entry.message.source, hex);
+        }
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // Replace the old cache in one step so a crash never leaves half a file, the temp
+        // name is per process since clients on one machine share the cache
+        let tmp = path.with_extension(format!("{}-{:016x}.tmp", std::process::id(), random_u64()));
+        fs::write(&tmp, content)?;
+        fs::rename(&tmp, path)
+    }
+}
+
+fn parse_cache_line(line: &str) -> Option<(SystemTime, DiscoveryMessage)> {
+    let mut fields = line.split('\t');
+    let millis: u64 = fields.next()?.parse().ok()?;
+    let source: SocketAddr = fields.next()?.parse().ok()?;
+    let hex = fields.next()?;
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        return None;
+    }
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    // Entries of an older protocol version fail to decode and are dropped
+    match DiscoveryPacket::decode(&bytes, &source) {
+        Ok(DiscoveryPacket::Announce(dm)) => Some((UNIX_EPOCH + Duration::from_millis(millis), dm)),
+        _ => None,
+    }
+}
+
+fn endpoint_text(dm: &DiscoveryMessage) -> String {
+    dm.endpoint().map_or(String::from("-"), |e| e.to_string())
+}
+
+fn format_ms(d: Duration) -> String {
+    format!("{:.1}ms", d.as_secs_f64() * 1000.0)
+}
+
+fn print_row<S: AsRef<str>>(cells: &[S], widths: &[usize]) {
+    let line: Vec<String> = cells
+        .iter()
+        .zip(widths)
+        .map(|(cell, w)| format!("{:<w$}", cell.as_ref(), w = w))
+        .collect();
+    println!("{}", line.join("  ").trim_end());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::global::helper::now_millis;
+
+    const TTL: Duration = Duration::from_secs(10);
+
+    fn age(table: &mut HostTable, id: &str, by: Duration) {
+        let entry = table.entries.get_mut(id).unwrap();
+        entry.last_seen = SystemTime::now() - by;
+    }
+
+    #[test]
+    fn new_host_appears_once() {
+        let mut table = HostTable::new(TTL, Vec::new());
+        let dm = DiscoveryMessage::test("a", "team", "192.0.2.1:4600");
+        assert!(matches!(table.upsert(dm.clone()), Some(HostEvent::Appeared(m)) if m == dm));
+        assert!(table.upsert(dm).is_none());
+        assert_eq!(table.len(), 1);
+    }
+
+    #[test]
+    fn same_id_at_a_new_endpoint_moves() {
+        let mut table = HostTable::new(TTL, Vec::new());
+        table.upsert(DiscoveryMessage::test("a", "team", "192.0.2.1:4600"));
+        let event = table.upsert(DiscoveryMessage::test("a", "team", "192.0.2.2:4600"));
+        assert!(matches!(
+            event,
+            Some(HostEvent::Moved { from, to })
+                if from == "192.0.2.1:4600".parse().unwrap() && to.endpoint() == Some("192.0.2.2:4600".parse().unwrap())
+        ));
+        assert_eq!(table.len(), 1);
+    }
+
+    #[test]
+    fn stale_host_appears_when_confirmed() {
+        let mut table = HostTable::new(TTL, Vec::new());
+        table.upsert(DiscoveryMessage::test("a", "team", "192.0.2.1:4600"));
+        table.entries.get_mut("a").unwrap().stale = true;
+        assert!(matches!(
+            table.upsert(DiscoveryMessage::test("a", "team", "192.0.2.2:4600")),
+            Some(HostEvent::Appeared(_))
+        ));
+        assert!(!table.entries["a"].stale);
+    }
+
+    #[test]
+    fn other_groups_are_ignored() {
+        let mut table = HostTable::new(TTL, vec![String::from("team")]);
+        assert!(
+            table
+                .upsert(DiscoveryMessage::test("a", "other", "192.0.2.1:4600"))
+                .is_none()
+        );
+        assert!(
+            table
+                .upsert(DiscoveryMessage::test("b", "team", "192.0.2.2:4600"))
+                .is_some()
+        );
+        assert_eq!(table.len(), 1);
+    }
+
+    #[test]
+    fn narrowing_the_groups_forgets_hosts() {
+        let mut table = HostTable::new(TTL, Vec::new());
+        table.upsert(DiscoveryMessage::test("a", "team", "192.0.2.1:4600"));
+        table.upsert(DiscoveryMessage::test("b", "other", "192.0.2.2:4600"));
+        table.set_groups(vec![String::from("other")]);
+        assert_eq!(
+            table
+                .values()
+                .map(|m| m.host_id.as_str())
+                .collect::<Vec<_>>(),
+            ["b"]
+        );
+    }
+
+    #[test]
+    fn silent_hosts_expire_after_the_ttl() {
+        let mut table = HostTable::new(TTL, Vec::new());
+        table.upsert(DiscoveryMessage::test("a", "team", "192.0.2.1:4600"));
+        table.upsert(DiscoveryMessage::test("b", "team", "192.0.2.2:4600"));
+        age(&mut table, "a", TTL - Duration::from_secs(1));
+        age(&mut table, "b", TTL + Duration::from_secs(1));
+        let events = table.expire();
+        assert!(matches!(events.as_slice(), [HostEvent::WentAway(m)] if m.host_id == "b"));
+        assert_eq!(table.len(), 1);
+    }
+
+    #[test]
+    fn stale_hosts_do_not_expire() {
+        let mut table = HostTable::new(TTL, Vec::new());
+        table.upsert(DiscoveryMessage::test("a", "team", "192.0.2.1:4600"));
+        table.entries.get_mut("a").unwrap().stale = true;
+        age(&mut table, "a", TTL * 10);
+        assert!(table.expire().is_empty());
+        assert_eq!(table.len(), 1);
+    }
+
+    #[test]
+    fn goodbye_removes_by_id() {
+        let mut table = HostTable::new(TTL, Vec::new());
+        table.upsert(DiscoveryMessage::test("a", "team", "192.0.2.1:4600"));
+        assert!(table.remove_by_id("b").is_none());
+        assert!(matches!(table.remove_by_id("a"), Some(HostEvent::Left(m)) if m.host_id == "a"));
+        assert!(table.is_empty());
+    }
+
+    fn cache_line(millis: u128, dm: &DiscoveryMessage) -> String {
+        let hex: String = dm.encode().iter().map(|b| format!("{b:02x}")).collect();
+        format!("{}\t{}\t{}", millis, dm.source, hex)
+    }
+
+    fn cache_path() -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("udp-discovery-test-{:016x}", random_u64()))
+    }
+
+    #[test]
+    fn cache_line_round_trips() {
+        let dm = DiscoveryMessage::test("a", "team", "192.0.2.1:4600");
+        let (last_seen, parsed) = parse_cache_line(&cache_line(1_000, &dm)).unwrap();
+        assert_eq!(last_seen, UNIX_EPOCH + Duration::from_secs(1));
+        assert_eq!(parsed.host_id, dm.host_id);
+        assert_eq!(parsed.endpoints, dm.endpoints);
+    }
+
+    #[test]
+    fn bad_cache_lines_are_skipped() {
+        let line = cache_line(
+            1_000,
+            &DiscoveryMessage::test("a", "team", "192.0.2.1:4600"),
+        );
+        let (head, hex) = line.rsplit_once('\t').unwrap();
+        for bad in [
+            String::new(),
+            format!("{head}\t{}", &hex[1..]),
+            format!("{head}\t{}zz", &hex[2..]),
+            format!("{head}\t{}", &hex[..hex.len() - 2]),
+            format!("{head}\t{}é", &hex[1..]),
+            format!("soon\t{}", line.split_once('\t').unwrap().1),
+            line.replacen("0.0.0.0:0", "nowhere", 1),
+            String::from(head),
+        ] {
+            assert!(parse_cache_line(&bad).is_none(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn save_and_load_restore_hosts_as_stale() {
+        let path = cache_path();
+        let mut table = HostTable::new(TTL, Vec::new());
+        table.upsert(DiscoveryMessage::test("a", "team", "192.0.2.1:4600"));
+        table.upsert(DiscoveryMessage::test("b", "other", "192.0.2.2:4600"));
+        table.save(&path).unwrap();
+
+        let mut loaded = HostTable::new(TTL, vec![String::from("team")]);
+        assert_eq!(loaded.load(&path).unwrap(), 1);
+        assert!(loaded.entries["a"].stale);
+        assert_eq!(
+            loaded.entries["a"].message.endpoints,
+            table.entries["a"].message.endpoints
+        );
+
+        // Known hosts are not replaced by their cached copy
+        loaded.entries.get_mut("a").unwrap().stale = false;
+        assert_eq!(loaded.load(&path).unwrap(), 0);
+        assert!(!loaded.entries["a"].stale);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn old_cache_entries_are_not_loaded() {
+        let path = cache_path();
+        let now = u128::from(now_millis());
+        let old = now - CACHE_MAX_AGE.as_millis() - 60_000;
+        let recent = now - CACHE_MAX_AGE.as_millis() + 60_000;
+        let content = [
+            cache_line(old, &DiscoveryMessage::test("a", "team", "192.0.2.1:4600")),
+            cache_line(
+                recent,
+                &DiscoveryMessage::test("b", "team", "192.0.2.2:4600"),
+            ),
+            String::from("garbage"),
+        ]
+        .join("\n");
+        fs::write(&path, content).unwrap();
+
+        let mut table = HostTable::new(TTL, Vec::new());
+        assert_eq!(table.load(&path).unwrap(), 1);
+        assert!(table.entries.contains_key("b"));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn missing_cache_loads_nothing() {
+        let mut table = HostTable::new(TTL, Vec::new());
+        assert_eq!(table.load(&cache_path()).unwrap(), 0);
+    }
+
+    #[test]
+    fn clients_sharing_a_cache_save_at_once() {
+        let dir = cache_path();
+        let path = dir.join("known-hosts");
+        let mut table = HostTable::new(TTL, Vec::new());
+        table.upsert(DiscoveryMessage::test("a", "team", "192.0.2.1:4600"));
+        std::thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| table.save(&path).unwrap());
+            }
+        });
+
+        let files: Vec<_> = fs::read_dir(&dir).unwrap().collect();
+        assert_eq!(files.len(), 1);
+        assert_eq!(HostTable::new(TTL, Vec::new()).load(&path).unwrap(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,5 +1,12 @@
+pub mod announcer;
+pub mod auth;
+pub mod background;
 pub mod client;
 pub mod command;
 pub mod discovery;
 pub mod host;
+pub mod host_table;
+pub mod protocol;
+pub mod relay;
+pub mod rtt;
 pub mod user;
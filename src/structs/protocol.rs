@@ -0,0 +1,469 @@
+use core::fmt;
+use std::{io, time::Duration};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::global::helper::format_age;
+
+// Largest frame body any message encodes to (type and two full strings),
+// a bigger length prefix means a broken or hostile peer
+pub const MAX_FRAME_LEN: usize = 1 + 2 * (2 + u16::MAX as usize);
+
+// Most members listed in one members message, with names of the 32 characters
+// a host allows these still fit a frame
+const MAX_MEMBERS: usize = 512;
+
+const TYPE_HELLO: u8 = 1;
+const TYPE_CHAT: u8 = 2;
+const TYPE_JOIN: u8 = 3;
+const TYPE_LEAVE: u8 = 4;
+const TYPE_PING: u8 = 5;
+const TYPE_PONG: u8 = 6;
+const TYPE_ERROR: u8 = 7;
+const TYPE_SYSTEM: u8 = 8;
+const TYPE_WHO: u8 = 9;
+const TYPE_MEMBERS: u8 = 10;
+
+/*
+ * Chat protocol between host and clients over TCP (all integers big endian)
+ *
+ * frame  => | length u32 | type u8 | payload |, length counts type and payload
+ * hello  => | type 1 | name str | password str |
+ * chat   => | type 2 | from str | text str |
+ * join   => | type 3 | name str |
+ * leave  => | type 4 | name str | reason u8 |
+ * ping   => | type 5 | nonce u64 |
+ * pong   => | type 6 | nonce u64 |
+ * error  => | type 7 | text str |
+ * system => | type 8 | text str |
+ * who    => | type 9 |
+ * members => | type 10 | count u16 | (name str | connected secs u64) * count |
+ *
+ * str => u16 length followed by that many UTF-8 bytes
+ * reason => 0 quit, 1 timeout, 2 kicked
+ *
+ * A client starts with hello, the password is empty when the room has none. The host
+ * answers with system when it lets the client in, error otherwise. Chat a client sends
+ * has an empty `from`, the host fills in the nickname of the connection. Who asks the host
+ * for members, the host itself first.
+ */
+// Why a member is no longer in the room
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LeaveReason {
+    Quit,
+    // Stopped answering, or the connection broke
+    Timeout,
+    Kicked,
+}
+
+impl LeaveReason {
+    fn from_u8(b: u8) -> Result<LeaveReason, String> {
+        match b {
+            0 => Ok(LeaveReason::Quit),
+            1 => Ok(LeaveReason::Timeout),
+            2 => Ok(LeaveReason::Kicked),
+            b => Err(format!("Unknown leave reason {}", b)),
+        }
+    }
+}
+
+impl fmt::Display for LeaveReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LeaveReason::Quit => write!(f, "quit"),
+            LeaveReason::Timeout => write!(f, "timed out"),
+            LeaveReason::Kicked => write!(f, "kicked"),
+        }
+    }
+}
+
+// Someone in the room and how long they have been in it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemberInfo {
+    pub name: String,
+    pub connected_secs: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChatMessage {
+    Hello { name: String, password: String },
+    Chat { from: String, text: String },
+    Join { name: String },
+    Leave { name: String, reason: LeaveReason },
+    Ping { nonce: u64 },
+    Pong { nonce: u64 },
+    Error { text: String },
+    System { text: String },
+    Who,
+    Members { members: Vec<MemberInfo> },
+}
+
+impl ChatMessage {
+    // Whole frame, length prefix included
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![0; 4];
+        match self {
+            ChatMessage::Hello { name, password } => {
+                buf.push(TYPE_HELLO);
+                put_str(&mut buf, name);
+                put_str(&mut buf, password);
+            }
+            ChatMessage::Chat { from, text } => {
+                buf.push(TYPE_CHAT);
+                put_str(&mut buf, from);
+                put_str(&mut buf, text);
+            }
+            ChatMessage::Join { name } => {
+                buf.push(TYPE_JOIN);
+                put_str(&mut buf, name);
+            }
+            ChatMessage::Leave { name, reason } => {
+                buf.push(TYPE_LEAVE);
+                put_str(&mut buf, name);
+                buf.push(*reason as u8);
+            }
+            ChatMessage::Ping { nonce } => {
+                buf.push(TYPE_PING);
+                buf.extend_from_slice(&nonce.to_be_bytes());
+            }
+            ChatMessage::Pong { nonce } => {
+                buf.push(TYPE_PONG);
+                buf.extend_from_slice(&nonce.to_be_bytes());
+            }
+            ChatMessage::Error { text } => {
+                buf.push(TYPE_ERROR);
+                put_str(&mut buf, text);
+            }
+            ChatMessage::System { text } => {
+                buf.push(TYPE_SYSTEM);
+                put_str(&mut buf, text);
+            }
+            ChatMessage::Who => buf.push(TYPE_WHO),
+            ChatMessage::Members { members } => {
+                buf.push(TYPE_MEMBERS);
+                let members = &members[..members.len().min(MAX_MEMBERS)];
+                buf.extend_from_slice(&(members.len() as u16).to_be_bytes());
+                for m in members {
+                    put_str(&mut buf, &m.name);
+                    buf.extend_from_slice(&m.connected_secs.to_be_bytes());
+                }
+            }
+        }
+        let len = (buf.len() - 4) as u32;
+        buf[..4].copy_from_slice(&len.to_be_bytes());
+        buf
+    }
+
+    // Parse one frame body, without its length prefix
+    pub fn decode(body: &[u8]) -> Result<ChatMessage, String> {
+        let mut r = Reader { buf: body, pos: 0 };
+        let message = match r.u8()? {
+            TYPE_HELLO => ChatMessage::Hello {
+                name: r.str()?,
+                password: r.str()?,
+            },
+            TYPE_CHAT => ChatMessage::Chat {
+                from: r.str()?,
+                text: r.str()?,
+            },
+            TYPE_JOIN => ChatMessage::Join { name: r.str()? },
+            TYPE_LEAVE => ChatMessage::Leave {
+                name: r.str()?,
+                reason: LeaveReason::from_u8(r.u8()?)?,
+            },
+            TYPE_PING => ChatMessage::Ping { nonce: r.u64()? },
+            TYPE_PONG => ChatMessage::Pong { nonce: r.u64()? },
+            TYPE_ERROR => ChatMessage::Error { text: r.str()? },
+            TYPE_SYSTEM => ChatMessage::System { text: r.str()? },
+            TYPE_WHO => ChatMessage::Who,
+            TYPE_MEMBERS => {
+                let count = r.u16()?;
+                let mut members = Vec::with_capacity(count as usize);
+                for _ in 0..count {
+                    members.push(MemberInfo {
+                        name: r.str()?,
+                        connected_secs: r.u64()?,
+                    });
+                }
+                ChatMessage::Members { members }
+            }
+            t => return Err(format!("Unknown message type {}", t)),
+        };
+        if r.pos != body.len() {
+            return Err(String::from("Trailing bytes after message"));
+        }
+        Ok(message)
+    }
+}
+
+// How a message is shown to the people in the room
+impl fmt::Display for ChatMessage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChatMessage::Hello { name, .. } => write!(f, "* {} says hello", name),
+            ChatMessage::Chat { from, text } => write!(f, "{}: {}", from, text),
+            ChatMessage::Join { name } => write!(f, "* {} joined", name),
+            ChatMessage::Leave { name, reason } => write!(f, "* {} left ({})", name, reason),
+            ChatMessage::Ping { nonce } => write!(f, "* ping {:x}", nonce),
+            ChatMessage::Pong { nonce } => write!(f, "* pong {:x}", nonce),
+            ChatMessage::Error { text } => write!(f, "! {}", text),
+            ChatMessage::System { text } => write!(f, "* {}", text),
+            ChatMessage::Who => write!(f, "* who is here?"),
+            ChatMessage::Members { members } => {
+                write!(f, "* {} in the room:", members.len())?;
+                for m in members {
+                    let age = format_age(Duration::from_secs(m.connected_secs));
+                    write!(f, "\n    {:<20} {}", m.name, age)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+// Take the first complete frame out of `buf`, None until one has fully arrived
+pub fn take_frame(buf: &mut Vec<u8>) -> Result<Option<ChatMessage>, String> {
+    if buf.len() < 4 {
+        return Ok(None);
+    }
+    let len = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+    if len == 0 || len > MAX_FRAME_LEN {
+        return Err(format!("Invalid frame length {}", len));
+    }
+    if buf.len() < 4 + len {
+        return Ok(None);
+    }
+    let message = ChatMessage::decode(&buf[4..4 + len]);
+    buf.drain(..4 + len);
+    message.map(Some)
+}
+
+// Reads messages off a stream, safe to use in `select!` as partial frames stay buffered
+pub struct FrameReader<R> {
+    inner: R,
+    buf: Vec<u8>,
+}
+
+impl<R: AsyncRead + Unpin> FrameReader<R> {
+    pub fn new(inner: R) -> FrameReader<R> {
+        FrameReader {
+            inner,
+            buf: Vec::new(),
+        }
+    }
+
+    // Next message, None once the peer closed the stream between frames
+    pub async fn next(&mut self) -> io::Result<Option<ChatMessage>> {
+        let mut chunk = [0; 4096];
+        loop {
+            if let Some(message) = take_frame(&mut self.buf)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+            {
+                return Ok(Some(message));
+            }
+            let n = self.inner.read(&mut chunk).await?;
+            if n == 0 {
+                if self.buf.is_empty() {
+                    return Ok(None);
+                }
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Stream closed inside a frame",
+                ));
+            }
+            self.buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+}
+
+pub async fn write_message<W: AsyncWrite + Unpin>(
+    w: &mut W,
+    message: &ChatMessage,
+) -> io::Result<()> {
+    w.write_all(&message.encode()).await
+}
+
+fn put_str(buf: &mut Vec<u8>, s: &str) {
+    // Strings longer than 65535 bytes are truncated on a char boundary
+    let mut end = s.len().min(u16::MAX as usize);
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    buf.extend_from_slice(&(end as u16).to_be_bytes());
+    buf.extend_from_slice(&s.as_bytes()[..end]);
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if self.buf.len() - self.pos < n {
+            return Err(String::from("Message too short"));
+        }
+        let s = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(s)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        let b = self.take(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn str(&mut self) -> Result<String, String> {
+        let b = self.take(2)?;
+        let len = u16::from_be_bytes([b[0], b[1]]) as usize;
+        let b = self.take(len)?;
+        String::from_utf8(b.to_vec()).map_err(|_| String::from("Invalid UTF-8 in message"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn all_messages() -> Vec<ChatMessage> {
+        vec![
+            ChatMessage::Hello {
+                name: String::from("alice"),
+                password: String::from("secret"),
+            },
+            ChatMessage::Chat {
+                from: String::from("bob"),
+                text: String::from("two\nlines"),
+            },
+            ChatMessage::Join {
+                name: String::from("carol"),
+            },
+            ChatMessage::Leave {
+                name: String::from("dave"),
+                reason: LeaveReason::Kicked,
+            },
+            ChatMessage::Ping { nonce: 42 },
+            ChatMessage::Pong { nonce: u64::MAX },
+            ChatMessage::Error {
+                text: String::from("name taken"),
+            },
+            ChatMessage::System {
+                text: String::from("héllo ✓"),
+            },
+            ChatMessage::Who,
+            ChatMessage::Members {
+                members: vec![
+                    MemberInfo {
+                        name: String::from("host"),
+                        connected_secs: 3600,
+                    },
+                    MemberInfo {
+                        name: String::from("alice"),
+                        connected_secs: 0,
+                    },
+                ],
+            },
+        ]
+    }
+
+    #[test]
+    fn every_message_round_trips() {
+        for message in all_messages() {
+            let mut buf = message.encode();
+            assert_eq!(take_frame(&mut buf), Ok(Some(message)));
+            assert!(buf.is_empty());
+        }
+    }
+
+    #[test]
+    fn length_prefix_counts_type_and_payload() {
+        let frame = ChatMessage::Ping { nonce: 1 }.encode();
+        assert_eq!(frame.len(), 4 + 1 + 8);
+        assert_eq!(&frame[..4], &9u32.to_be_bytes());
+    }
+
+    #[test]
+    fn partial_frames_wait_for_the_rest() {
+        let frame = ChatMessage::System {
+            text: String::from("hi"),
+        }
+        .encode();
+        let mut buf = Vec::new();
+        for byte in &frame[..frame.len() - 1] {
+            buf.push(*byte);
+            assert_eq!(take_frame(&mut buf), Ok(None));
+        }
+        buf.push(frame[frame.len() - 1]);
+        assert!(take_frame(&mut buf).unwrap().is_some());
+    }
+
+    #[test]
+    fn back_to_back_frames_come_out_in_order() {
+        let messages = all_messages();
+        let mut buf: Vec<u8> = messages.iter().flat_map(|m| m.encode()).collect();
+        for message in messages {
+            assert_eq!(take_frame(&mut buf), Ok(Some(message)));
+        }
+        assert_eq!(take_frame(&mut buf), Ok(None));
+    }
+
+    #[test]
+    fn bad_lengths_are_rejected() {
+        let mut empty = 0u32.to_be_bytes().to_vec();
+        assert!(take_frame(&mut empty).is_err());
+        let mut huge = ((MAX_FRAME_LEN + 1) as u32).to_be_bytes().to_vec();
+        assert!(take_frame(&mut huge).is_err());
+    }
+
+    #[test]
+    fn malformed_bodies_are_rejected() {
+        assert!(ChatMessage::decode(&[99]).is_err());
+        assert!(ChatMessage::decode(&[TYPE_PING, 0, 0]).is_err());
+        assert!(ChatMessage::decode(&[TYPE_JOIN, 0, 2, 0xff, 0xfe]).is_err());
+        assert!(ChatMessage::decode(&[TYPE_JOIN, 0, 0, 7]).is_err());
+        assert!(ChatMessage::decode(&[TYPE_LEAVE, 0, 0, 9]).is_err());
+        assert!(ChatMessage::decode(&[TYPE_MEMBERS, 0, 1]).is_err());
+    }
+
+    #[test]
+    fn long_strings_are_truncated_on_a_char_boundary() {
+        let text = "é".repeat(40_000);
+        let mut buf = ChatMessage::System { text }.encode();
+        match take_frame(&mut buf) {
+            Ok(Some(ChatMessage::System { text })) => {
+                assert!(text.len() <= u16::MAX as usize);
+                assert!(text.chars().all(|c| c == 'é'));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn frame_reader_reads_until_clean_eof() {
+        let messages = all_messages();
+        let bytes: Vec<u8> = messages.iter().flat_map(|m| m.encode()).collect();
+        let mut reader = FrameReader::new(&bytes[..]);
+        for message in messages {
+            assert_eq!(reader.next().await.unwrap(), Some(message));
+        }
+        assert_eq!(reader.next().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn frame_reader_fails_on_eof_inside_a_frame() {
+        let frame = ChatMessage::Join {
+            name: String::from("eve"),
+        }
+        .encode();
+        let mut reader = FrameReader::new(&frame[..frame.len() - 2]);
+        assert!(reader.next().await.is_err());
+    }
+}
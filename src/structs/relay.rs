@@ -0,0 +1,299 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use tokio::{select, sync::watch};
+
+use crate::global::{
+    config::{Config, DiscoveryMode},
+    helper::{bind_addr, bind_shared_udp, join_discovery_group, quit_task_handler},
+};
+
+use super::{
+    announcer::{Announcer, broadcast_interfaces, interface_of},
+    auth::PacketAuth,
+    discovery::{DiscoveryPacket, generate_host_id},
+};
+
+// Copies of one packet arriving within this window are relayed once
+const DUPLICATE_WINDOW: Duration = Duration::from_millis(100);
+
+// Re-emits the discovery packets heard on one interface on every other one,
+// so hosts of one subnet are visible to clients of the others
+pub struct Relay {
+    id: String,
+    discovery: DiscoveryMode,
+    interfaces: Vec<String>,
+    secret: Option<Vec<u8>>,
+    max_hops: u8,
+    // Last time a packet of (host id, goodbye) was relayed
+    recent: HashMap<(String, bool), Instant>,
+}
+
+impl Relay {
+    pub fn new(config: &Config) -> Relay {
+        Relay {
+            id: generate_host_id(),
+            discovery: config.discovery,
+            interfaces: config.interfaces.clone(),
+            secret: config.secret.clone(),
+            max_hops: config.max_hops,
+            recent: HashMap::new(),
+        }
+    }
+
+    pub async fn run(&mut self, host: &str, client_port: u16) {
+        if let DiscoveryMode::MulticastV6 { .. } = self.discovery {
+            println!("Relaying needs --discovery broadcast or multicast");
+            return;
+        }
+
+        let target_addr = self.discovery.target(client_port);
+        let socket = match bind_shared_udp(bind_addr(host, client_port, &target_addr)) {
+            Ok(s) => s,
+            Err(e) => {
+                println!("Failed to listen on port {client_port}, Error: {e}");
+                return;
+            }
+        };
+        let joined = match self.discovery {
+            // Hear the group on every interface, not only the default one
+            DiscoveryMode::Multicast { group, .. } => broadcast_interfaces(&self.interfaces)
+                .into_iter()
+                .try_for_each(|(_, ip, _)| socket.join_multicast_v4(group, ip)),
+            mode => join_discovery_group(&socket, mode, host),
+        };
+        if let Err(e) = joined {
+            println!(
+                "Failed to join discovery group {}, Error: {e}",
+                target_addr.ip()
+            );
+            return;
+        }
+
+        let announcer =
+            match Announcer::bind(host, self.discovery, client_port, &self.interfaces).await {
+                Ok(a) => a,
+                Err(e) => {
+                    println!("Failed to start relaying, Error: {e}");
+                    return;
+                }
+            };
+        announcer.print_routes();
+
+        // Setup shutdown signal
+        let (shutdown_tx, mut shutdown_rx) = watch::channel(false);
+
+        // Spawn task to read stdin and look for 'q'
+        let quit_task = quit_task_handler(shutdown_tx).await;
+
+        println!(
+            "> Relaying as [{}] up to {} hop(s), enter q then ENTER to stop",
+            self.id, self.max_hops
+        );
+
+        let mut auth = PacketAuth::new(self.secret.clone());
+        let mut buf = [0; 1024];
+        loop {
+            select! {
+                res = shutdown_rx.changed() => {
+                    if res.is_err() || *shutdown_rx.borrow() {
+                        break;
+                    }
+                }
+                result = socket.recv_from(&mut buf) => {
+                    match result {
+                        Ok((n, addr)) => {
+                            let body = match auth.open(&buf[..n]) {
+                                Ok(body) => body,
+                                Err(e) => {
+                                    eprintln!("> rejected packet from {}: {}", addr, e);
+                                    continue;
+                                }
+                            };
+                            if let Some(packet) = self.relayed(body, &addr) {
+                                let ingress = interface_of(addr.ip());
+                                if let Err(e) = announcer.send_except(&auth, &packet, ingress.as_deref()).await {
+                                    eprintln!("Failed to relay: {}", e);
+                                }
+                            }
+                        }
+                        Err(e) => {
+                            eprintln!("UDP recv error: {}", e);
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+
+        quit_task.abort();
+    }
+
+    // Packet to send on for a received one, None if it must not be relayed
+    fn relayed(&mut self, body: &[u8], addr: &SocketAddr) -> Option<Vec<u8>> {
+        let packet = match DiscoveryPacket::decode(body, addr) {
+            Ok(p) => p,
+            Err(e) => {
+                eprintln!("> ignored packet from {}: {}", addr, e);
+                return None;
+            }
+        };
+        let (key, path) = match &packet {
+            DiscoveryPacket::Announce(dm) => ((dm.host_id.clone(), false), &dm.path),
+            DiscoveryPacket::Goodbye { host_id, path } => ((host_id.clone(), true), path),
+            // Hosts answer these by unicast, that cannot cross the relay
+            DiscoveryPacket::Probe
+            | DiscoveryPacket::Ping { .. }
+            | DiscoveryPacket::Pong { .. } => return None,
+        };
+
+        // Our own packet came back, or it went around too many relays
+        if path.relays.contains(&self.id) || path.hops >= self.max_hops {
+            return None;
+        }
+        let path = path.through(&self.id);
+
+        // The same packet reached us through several paths
+        let now = Instant::now();
+        let first = match self.recent.insert(key, now) {
+            Some(last) if now.duration_since(last) < DUPLICATE_WINDOW => return None,
+            last => last.is_none(),
+        };
+
+        match packet {
+            DiscoveryPacket::Announce(mut dm) => {
+                if first {
+                    println!(
+                        "> relaying {} [{}] from {} ({} hop(s))",
+                        dm.name, dm.host_id, addr, path.hops
+                    );
+                }
+                dm.path = path;
+                Some(dm.encode())
+            }
+            DiscoveryPacket::Goodbye { host_id, .. } => {
+                self.recent.remove(&(host_id.clone(), false));
+                println!("> relaying goodbye of [{}] from {}", host_id, addr);
+                Some(DiscoveryPacket::encode_goodbye(&host_id, &path))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::discovery::{DiscoveryMessage, RelayPath};
+
+    fn relay(max_hops: u8) -> Relay {
+        Relay {
+            id: String::from("relay-self"),
+            discovery: DiscoveryMode::Broadcast,
+            interfaces: Vec::new(),
+            secret: None,
+            max_hops,
+            recent: HashMap::new(),
+        }
+    }
+
+    fn announce(path: RelayPath) -> Vec<u8> {
+        let mut dm = DiscoveryMessage::test("host-a", "default", "192.0.2.1:4600");
+        dm.path = path;
+        dm.encode()
+    }
+
+    fn from() -> SocketAddr {
+        "192.0.2.1:40000".parse().unwrap()
+    }
+
+    fn path_of(packet: &[u8]) -> RelayPath {
+        match DiscoveryPacket::decode(packet, &from()) {
+            Ok(DiscoveryPacket::Announce(dm)) => dm.path,
+            Ok(DiscoveryPacket::Goodbye { path, .. }) => path,
+            _ => panic!("not an announce or goodbye"),
+        }
+    }
+
+    #[test]
+    fn relayed_packets_carry_our_hop() {
+        let mut relay = relay(4);
+        let out = relay
+            .relayed(&announce(RelayPath::default()), &from())
+            .unwrap();
+        assert_eq!(path_of(&out), RelayPath::default().through("relay-self"));
+    }
+
+    #[test]
+    fn hop_limit_stops_relaying() {
+        let mut relay = relay(2);
+        let two_hops = RelayPath::default().through("r1").through("r2");
+        assert!(relay.relayed(&announce(two_hops), &from()).is_none());
+
+        let one_hop = RelayPath::default().through("r1");
+        let out = relay.relayed(&announce(one_hop), &from()).unwrap();
+        assert_eq!(path_of(&out).hops, 2);
+    }
+
+    #[test]
+    fn own_packets_are_not_relayed_again() {
+        let mut relay = relay(8);
+        let looped = RelayPath::default().through("relay-self").through("r2");
+        assert!(relay.relayed(&announce(looped), &from()).is_none());
+    }
+
+    #[test]
+    fn duplicates_within_the_window_are_relayed_once() {
+        let mut relay = relay(4);
+        let packet = announce(RelayPath::default());
+        assert!(relay.relayed(&packet, &from()).is_some());
+        let other_path = announce(RelayPath::default().through("r1"));
+        assert!(relay.relayed(&other_path, &from()).is_none());
+
+        // Periodic announces after the window go through again
+        let last = relay
+            .recent
+            .get_mut(&(String::from("host-a"), false))
+            .unwrap();
+        *last -= DUPLICATE_WINDOW;
+        assert!(relay.relayed(&packet, &from()).is_some());
+    }
+
+    #[test]
+    fn goodbye_is_relayed_right_after_the_announce() {
+        let mut relay = relay(4);
+        assert!(
+            relay
+                .relayed(&announce(RelayPath::default()), &from())
+                .is_some()
+        );
+        let goodbye = DiscoveryPacket::encode_goodbye("host-a", &RelayPath::default());
+        assert!(relay.relayed(&goodbye, &from()).is_some());
+        assert!(relay.relayed(&goodbye, &from()).is_none());
+        // The host may come back at once
+        assert!(
+            relay
+                .relayed(&announce(RelayPath::default()), &from())
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn probes_and_pings_are_not_relayed() {
+        let mut relay = relay(4);
+        assert!(
+            relay
+                .relayed(&DiscoveryPacket::encode_probe(), &from())
+                .is_none()
+        );
+        assert!(
+            relay
+                .relayed(&DiscoveryPacket::encode_ping(7), &from())
+                .is_none()
+        );
+        assert!(relay.relayed(b"garbage", &from()).is_none());
+    }
+}
@@ -0,0 +1,183 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+// Pings remembered per host, latency, loss and jitter are taken over these
+const WINDOW: usize = 20;
+
+// A ping not answered within this counts as lost
+const PING_TIMEOUT: Duration = Duration::from_secs(2);
+
+struct Ping {
+    nonce: u64,
+    sent: Instant,
+    rtt: Option<Duration>,
+}
+
+// Round trip times of the last pings sent to one host
+#[derive(Default)]
+pub struct RttStats {
+    pings: VecDeque<Ping>,
+}
+
+impl RttStats {
+    pub fn sent(&mut self, nonce: u64) {
+        self.sent_at(nonce, Instant::now());
+    }
+
+    fn sent_at(&mut self, nonce: u64, now: Instant) {
+        if self.pings.len() == WINDOW {
+            self.pings.pop_front();
+        }
+        self.pings.push_back(Ping {
+            nonce,
+            sent: now,
+            rtt: None,
+        });
+    }
+
+    // Record the pong for `nonce`, false if it is not one of our pings or came too late
+    pub fn answered(&mut self, nonce: u64) -> bool {
+        self.answered_at(nonce, Instant::now())
+    }
+
+    fn answered_at(&mut self, nonce: u64, now: Instant) -> bool {
+        match self
+            .pings
+            .iter_mut()
+            .find(|p| p.nonce == nonce && p.rtt.is_none())
+        {
+            // Already counted as lost, a late pong must not turn it back
+            Some(p) if now.duration_since(p.sent) > PING_TIMEOUT => false,
+            Some(p) => {
+                p.rtt = Some(now.duration_since(p.sent));
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn samples(&self) -> impl Iterator<Item = Duration> + '_ {
+        self.pings.iter().filter_map(|p| p.rtt)
+    }
+
+    // Mean round trip time
+    pub fn latency(&self) -> Option<Duration> {
+        let count = self.samples().count() as u32;
+        (count > 0).then(|| self.samples().sum::<Duration>() / count)
+    }
+
+    // Fraction of the answered or timed out pings that got no answer
+    pub fn loss(&self) -> Option<f64> {
+        self.loss_at(Instant::now())
+    }
+
+    fn loss_at(&self, now: Instant) -> Option<f64> {
+        let settled = self
+            .pings
+            .iter()
+            .filter(|p| p.rtt.is_some() || now.duration_since(p.sent) > PING_TIMEOUT);
+        let (total, lost) = settled.fold((0, 0), |(total, lost), p| {
+            (total + 1, lost + p.rtt.is_none() as u32)
+        });
+        (total > 0).then(|| f64::from(lost) / f64::from(total))
+    }
+
+    // Mean difference between consecutive round trip times
+    pub fn jitter(&self) -> Option<Duration> {
+        let samples: Vec<Duration> = self.samples().collect();
+        let diffs = samples.windows(2).map(|w| w[0].abs_diff(w[1]));
+        let count = samples.len().saturating_sub(1) as u32;
+        (count > 0).then(|| diffs.sum::<Duration>() / count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    // Pings sent 1s apart, answered after the given round trip times
+    fn pinged(rtts: &[Option<u64>]) -> (RttStats, Instant) {
+        let start = Instant::now();
+        let mut stats = RttStats::default();
+        for (i, rtt) in rtts.iter().enumerate() {
+            let sent = start + Duration::from_secs(i as u64);
+            stats.sent_at(i as u64, sent);
+            if let Some(rtt) = rtt {
+                assert!(stats.answered_at(i as u64, sent + ms(*rtt)));
+            }
+        }
+        (stats, start + Duration::from_secs(rtts.len() as u64))
+    }
+
+    #[test]
+    fn nothing_answered_has_no_stats() {
+        let (stats, _) = pinged(&[]);
+        assert_eq!(stats.latency(), None);
+        assert_eq!(stats.jitter(), None);
+        assert_eq!(stats.loss(), None);
+    }
+
+    #[test]
+    fn latency_is_the_mean_round_trip() {
+        let (stats, _) = pinged(&[Some(10), Some(30), None, Some(20)]);
+        assert_eq!(stats.latency(), Some(ms(20)));
+    }
+
+    #[test]
+    fn jitter_is_the_mean_difference_of_consecutive_samples() {
+        let (stats, _) = pinged(&[Some(10), Some(30), Some(20)]);
+        assert_eq!(stats.jitter(), Some(ms(15)));
+
+        let (single, _) = pinged(&[Some(10)]);
+        assert_eq!(single.jitter(), None);
+    }
+
+    #[test]
+    fn loss_counts_only_settled_pings() {
+        let (mut stats, now) = pinged(&[Some(10), None, Some(10), None]);
+        // The last ping went out a second ago, not lost yet
+        assert_eq!(stats.loss_at(now), Some(1.0 / 3.0));
+
+        stats.sent_at(99, now);
+        assert_eq!(stats.loss_at(now + PING_TIMEOUT), Some(0.5));
+        assert_eq!(stats.loss_at(now + PING_TIMEOUT + ms(1)), Some(0.6));
+    }
+
+    #[test]
+    fn pong_after_the_timeout_is_not_recorded() {
+        let now = Instant::now();
+        let mut stats = RttStats::default();
+        stats.sent_at(1, now);
+        stats.sent_at(2, now);
+        assert!(stats.answered_at(1, now + PING_TIMEOUT));
+        assert!(!stats.answered_at(2, now + PING_TIMEOUT + ms(1)));
+        assert_eq!(stats.latency(), Some(PING_TIMEOUT));
+        assert_eq!(stats.loss_at(now + PING_TIMEOUT + ms(1)), Some(0.5));
+    }
+
+    #[test]
+    fn unknown_or_repeated_pongs_are_ignored() {
+        let now = Instant::now();
+        let mut stats = RttStats::default();
+        stats.sent_at(1, now);
+        assert!(!stats.answered_at(2, now + ms(5)));
+        assert!(stats.answered_at(1, now + ms(5)));
+        assert!(!stats.answered_at(1, now + ms(9)));
+        assert_eq!(stats.latency(), Some(ms(5)));
+    }
+
+    #[test]
+    fn only_the_last_window_of_pings_is_kept() {
+        let mut rtts = vec![Some(500); WINDOW];
+        rtts.extend(vec![Some(10); WINDOW]);
+        let (stats, _) = pinged(&rtts);
+        assert_eq!(stats.latency(), Some(ms(10)));
+        assert_eq!(stats.pings.len(), WINDOW);
+    }
+}
@@ -4,11 +4,21 @@ pub enum User {
     Client(Client),
     Host(Host),
 }
+impl User {
+    // Stop background work before the user is replaced or the program exits
+    pub async fn shutdown(&mut self, host: &str, client_port: u16) {
+        match self {
+            User::Client(c) => c.stop_discovery().await,
+            User::Host(h) => h.leave(host, client_port).await,
+        }
+    }
+}
+
 pub trait UserTrait {
     async fn execute_command(
         &mut self,
         c: &Command,
-        h: &String,
+        h: &str,
         cp: &u16,
         hp: &u16,
     ) -> Result<(), String>;